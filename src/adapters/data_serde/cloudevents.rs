@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+
+use crate::{
+    configuration,
+    ports::data_serde::{DataSerde, ValueMap},
+};
+
+use super::json::apply_mapping;
+
+/// Prefix of the context attributes in the extracted values. It's the same as the one of the
+/// headers in the Kafka binary mode, so attributes have the same name in both modes.
+pub const ATTRIBUTE_PREFIX: &str = "ce_";
+
+/// Extracts the values of CloudEvents in the structured JSON format. The fields of `data` are the
+/// payload. The context attributes (`type`, `source`, `subject`, `time`, extensions, ...) can be
+/// mapped with the `ce_` prefix like any other field, but are only added when mapped.
+#[derive(Clone)]
+pub struct CloudEventsDataSerde {
+    mapping: configuration::JsonMapping,
+    strict: bool,
+}
+
+impl CloudEventsDataSerde {
+    pub fn new(mapping: configuration::JsonMapping, strict: bool) -> anyhow::Result<Self> {
+        if strict && mapping.0.is_empty() {
+            anyhow::bail!("JSON mapping cannot be undefined or empty when strict mode is enabled");
+        }
+        Ok(CloudEventsDataSerde { mapping, strict })
+    }
+
+    /// Extracts the values of an event which is already parsed, along with its type.
+    pub fn extract_event(&self, event: serde_json::Value) -> anyhow::Result<CloudEvent> {
+        let serde_json::Value::Object(mut event) = event else {
+            anyhow::bail!("cloud event is not an object");
+        };
+        if !event.contains_key("specversion") {
+            anyhow::bail!("cloud event has no specversion");
+        }
+        if event.contains_key("data_base64") {
+            anyhow::bail!("cloud events with binary data (data_base64) are not supported");
+        }
+
+        let event_type = event.get("type").and_then(|value| value.as_str()).map(Into::into);
+        let mut values: ValueMap = match event.remove("data") {
+            Some(serde_json::Value::Object(data)) => data.into_iter().collect(),
+            Some(serde_json::Value::Null) | None => HashMap::new(),
+            Some(_) => anyhow::bail!("data of cloud event is not an object"),
+        };
+        let mut attributes: ValueMap = event
+            .into_iter()
+            .map(|(attribute, value)| (format!("{ATTRIBUTE_PREFIX}{attribute}"), value))
+            .collect();
+        for source in self.mapping.0.values() {
+            if let Some(value) = attributes.remove(source) {
+                values.insert(source.clone(), value);
+            }
+        }
+
+        Ok(CloudEvent { event_type, values: apply_mapping(values, &self.mapping, self.strict) })
+    }
+}
+
+/// The extracted values of a CloudEvent, with its type.
+pub struct CloudEvent {
+    pub event_type: Option<String>,
+    pub values: ValueMap,
+}
+
+#[async_trait]
+impl DataSerde for CloudEventsDataSerde {
+    async fn extract_values(&self, data: Vec<u8>) -> anyhow::Result<ValueMap> {
+        if data.is_empty() {
+            return Ok(HashMap::new());
+        }
+        Ok(self.extract_event(serde_json::from_slice(&data)?)?.values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_extract_values_with_attributes() {
+        let mapping = configuration::JsonMapping::from(HashMap::from_iter(vec![
+            ("id".to_string(), "sessionId".to_string()),
+            ("eventType".to_string(), "ce_type".to_string()),
+        ]));
+        let serde = CloudEventsDataSerde::new(mapping, false).unwrap();
+        let event = serde_json::json!({
+            "specversion": "1.0",
+            "id": "7b0c2f4e",
+            "type": "com.jucr.charging_session.started",
+            "source": "/charging-sessions",
+            "subject": "abc",
+            "time": "2024-09-01T12:00:00Z",
+            "data": { "sessionId": "abc", "status": "charging" },
+        });
+        let result = serde.extract_values(serde_json::to_vec(&event).unwrap()).await.unwrap();
+
+        assert_eq!(result.get("id"), Some(&serde_json::json!("abc")));
+        assert_eq!(
+            result.get("eventType"),
+            Some(&serde_json::json!("com.jucr.charging_session.started"))
+        );
+        assert_eq!(result.get("status"), Some(&serde_json::json!("charging")));
+        // Attributes which aren't mapped aren't part of the payload.
+        assert_eq!(result.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_extract_values_requires_specversion() {
+        let serde =
+            CloudEventsDataSerde::new(configuration::JsonMapping::default(), false).unwrap();
+        let data = serde_json::to_vec(&serde_json::json!({ "id": "abc" })).unwrap();
+
+        assert!(serde.extract_values(data).await.is_err());
+    }
+}
@@ -28,29 +28,38 @@ impl DataSerde for JsonDataSerde {
         if data.is_empty() {
             return Ok(HashMap::new());
         }
-        let mut values: ValueMap = serde_json::from_slice(&data)?;
-
-        let result = if self.strict {
-            let mut result: ValueMap = HashMap::new();
-            for (key, source) in &self.mapping.0 {
-                if let Some(value) = values.remove(source) {
-                    result.insert(key.clone(), value);
-                }
-            }
-            result
-        } else {
-            for (key, source) in &self.mapping.0 {
-                if let Some(value) = values.remove(source) {
-                    values.insert(key.clone(), value);
-                }
-            }
-            values
-        };
+        let values: ValueMap = serde_json::from_slice(&data)?;
+        let result = apply_mapping(values, &self.mapping, self.strict);
 
         Ok(result)
     }
 }
 
+/// Renames the fields according to the mapping. In strict mode, fields which aren't mapped are
+/// dropped.
+pub(super) fn apply_mapping(
+    mut values: ValueMap,
+    mapping: &configuration::JsonMapping,
+    strict: bool,
+) -> ValueMap {
+    if strict {
+        let mut result: ValueMap = HashMap::new();
+        for (key, source) in &mapping.0 {
+            if let Some(value) = values.remove(source) {
+                result.insert(key.clone(), value);
+            }
+        }
+        result
+    } else {
+        for (key, source) in &mapping.0 {
+            if let Some(value) = values.remove(source) {
+                values.insert(key.clone(), value);
+            }
+        }
+        values
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use configuration::JsonMapping;
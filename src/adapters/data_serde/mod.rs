@@ -1,5 +1,7 @@
+pub mod cloudevents;
 pub mod json;
 pub mod protobuf;
 
+pub use cloudevents::CloudEventsDataSerde;
 pub use json::JsonDataSerde;
 pub use protobuf::ProtobufDataSerde;
@@ -9,6 +9,7 @@ use graphql_client::GraphQLQuery;
 )]
 pub struct PublishSubgraphMutation;
 
+#[allow(clippy::derivable_impls)]
 impl Default for publish_subgraph_mutation::LaunchStatus {
     fn default() -> Self {
         publish_subgraph_mutation::LaunchStatus::LAUNCH_INITIATED
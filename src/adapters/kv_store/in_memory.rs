@@ -1,17 +1,35 @@
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::time::{Duration, Instant};
 
 use crate::ports::kv_store::{KvStore, KvStoreFactory};
 
+#[derive(Default)]
+struct State {
+    maps: HashMap<String, HashMap<String, Vec<u8>>>,
+    sorted_sets: HashMap<String, BTreeSet<(u64, Vec<u8>)>>,
+    leases: HashMap<String, (String, Instant)>,
+}
+
+/// All clones share the same underlying state, so every actor created from the same factory sees
+/// the same data.
 #[derive(Clone)]
 pub struct InMemoryKvStore {
-    store: HashMap<String, HashMap<String, Vec<u8>>>,
+    store: Arc<Mutex<State>>,
 }
 
 impl InMemoryKvStore {
     pub fn new() -> Self {
         Self { store: Default::default() }
     }
+
+    fn lock(&self) -> anyhow::Result<MutexGuard<'_, State>> {
+        self.store.lock().map_err(|_| anyhow::anyhow!("in-memory store lock poisoned"))
+    }
 }
 
 #[async_trait]
@@ -23,22 +41,31 @@ impl KvStore for InMemoryKvStore {
         value: Vec<u8>,
         ttl_ms: u64, // Ignored for now
     ) -> anyhow::Result<()> {
-        let map = self.store.entry(key.clone()).or_default();
-        map.insert(map_key.clone(), serde_json::to_vec(&value)?);
+        self.lock()?.maps.entry(key.clone()).or_default().insert(map_key.clone(), value);
         tracing::debug! { event = "map_key_inserted", key, map_key, ttl_ms };
         Ok(())
     }
 
-    async fn get_map(&mut self, key: String) -> anyhow::Result<HashMap<String, Vec<u8>>> {
-        if let Some(map) = self.store.get(&key) {
-            return Ok(map.clone());
-        }
+    async fn merge_map(
+        &mut self,
+        key: String,
+        values: HashMap<String, Vec<u8>>,
+        ttl_ms: u64, // Ignored for now
+    ) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        let mut state = self.lock()?;
+        let map = state.maps.entry(key.clone()).or_default();
+        let previous = map.clone();
+        map.extend(values);
+        tracing::debug! { event = "map_merged", key, ttl_ms };
+        Ok(previous)
+    }
 
-        Ok(HashMap::new())
+    async fn get_map(&mut self, key: String) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        Ok(self.lock()?.maps.get(&key).cloned().unwrap_or_default())
     }
 
     async fn delete_map_value(&mut self, key: String, map_key: String) -> anyhow::Result<()> {
-        if let Some(map) = self.store.get_mut(&key) {
+        if let Some(map) = self.lock()?.maps.get_mut(&key) {
             map.remove(&map_key);
             tracing::debug! { event = "map_value_deleted", key, map_key };
         }
@@ -47,31 +74,142 @@ impl KvStore for InMemoryKvStore {
     }
 
     async fn delete_map(&mut self, key: String) -> anyhow::Result<()> {
-        self.store.remove(&key);
+        self.lock()?.maps.remove(&key);
         tracing::debug! { event = "map_deleted", key };
 
         Ok(())
     }
+
+    async fn insert_sorted_set_member(
+        &mut self,
+        key: String,
+        member: Vec<u8>,
+        score: u64,
+    ) -> anyhow::Result<()> {
+        let mut state = self.lock()?;
+        let sorted_set = state.sorted_sets.entry(key.clone()).or_default();
+        sorted_set.retain(|(_, existing)| *existing != member);
+        sorted_set.insert((score, member));
+        tracing::debug! { event = "sorted_set_member_inserted", key, score };
+        Ok(())
+    }
+
+    async fn get_sorted_set_range(
+        &mut self,
+        key: String,
+        max_score: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let state = self.lock()?;
+        let members = state
+            .sorted_sets
+            .get(&key)
+            .map(|sorted_set| {
+                sorted_set
+                    .iter()
+                    .take_while(|(score, _)| *score <= max_score)
+                    .take(limit)
+                    .map(|(_, member)| member.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(members)
+    }
+
+    async fn claim_sorted_set_members(
+        &mut self,
+        key: String,
+        max_score: u64,
+        limit: usize,
+        score: u64,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut state = self.lock()?;
+        let Some(sorted_set) = state.sorted_sets.get_mut(&key) else {
+            return Ok(Vec::new());
+        };
+        let claimed: Vec<(u64, Vec<u8>)> = sorted_set
+            .iter()
+            .take_while(|(member_score, _)| *member_score <= max_score)
+            .take(limit)
+            .cloned()
+            .collect();
+        let mut members = Vec::with_capacity(claimed.len());
+        for entry in claimed {
+            sorted_set.remove(&entry);
+            members.push(entry.1);
+        }
+        sorted_set.extend(members.iter().map(|member| (score, member.clone())));
+        tracing::debug! { event = "sorted_set_members_claimed", key, count = members.len() };
+        Ok(members)
+    }
+
+    async fn delete_sorted_set_member(
+        &mut self,
+        key: String,
+        member: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let mut state = self.lock()?;
+        let Some(sorted_set) = state.sorted_sets.get_mut(&key) else {
+            return Ok(false);
+        };
+        let len = sorted_set.len();
+        sorted_set.retain(|(_, existing)| *existing != member);
+        let deleted = sorted_set.len() < len;
+        tracing::debug! { event = "sorted_set_member_deleted", key, deleted };
+        Ok(deleted)
+    }
+
+    async fn get_sorted_set_len(&mut self, key: String) -> anyhow::Result<u64> {
+        Ok(self
+            .lock()?
+            .sorted_sets
+            .get(&key)
+            .map(|sorted_set| sorted_set.len() as u64)
+            .unwrap_or(0))
+    }
+
+    async fn acquire_lease(
+        &mut self,
+        key: String,
+        owner: String,
+        ttl_ms: u64,
+    ) -> anyhow::Result<bool> {
+        let now = Instant::now();
+        let mut state = self.lock()?;
+        let acquired = match state.leases.get(&key) {
+            Some((holder, expires_at)) => *holder == owner || *expires_at <= now,
+            None => true,
+        };
+        if acquired {
+            state.leases.insert(key.clone(), (owner.clone(), now + Duration::from_millis(ttl_ms)));
+        }
+        tracing::trace! { event = "lease_requested", key, owner, acquired };
+        Ok(acquired)
+    }
+
+    fn clone_box(&self) -> Box<dyn KvStore> {
+        Box::new(self.clone())
+    }
 }
 
 #[derive(Clone)]
 pub struct InMemoryKvStoreFactory {
-    _client: InMemoryKvStore,
+    client: InMemoryKvStore,
 }
 
 impl InMemoryKvStoreFactory {
     pub fn new() -> Self {
-        Self { _client: InMemoryKvStore::new() }
+        Self { client: InMemoryKvStore::new() }
     }
 }
 
 #[async_trait]
 impl KvStoreFactory for InMemoryKvStoreFactory {
     async fn create(&self) -> anyhow::Result<Box<dyn KvStore>> {
-        Ok(Box::new(InMemoryKvStore::new()))
+        Ok(Box::new(self.client.clone()))
     }
 
     fn clone_box(&self) -> Box<dyn KvStoreFactory> {
-        Box::new(Self { _client: self._client.clone() })
+        Box::new(Self { client: self.client.clone() })
     }
 }
@@ -1,14 +1,202 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 use async_trait::async_trait;
 use config::Config;
 use redis::{AsyncCommands, ConnectionLike};
 use serde::Deserialize;
+use tokio::sync::Mutex;
 
 use crate::ports::kv_store::{KvStore, KvStoreFactory};
 
+/// Extends the lease when it is held by the owner, otherwise only takes it when it is free.
+const ACQUIRE_LEASE_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+return 0
+"#;
+
+/// Sets the field and its TTL at once, so the map can't be left without TTL in between.
+const INSERT_MAP_KEY_SCRIPT: &str = r#"
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+redis.call('PEXPIRE', KEYS[1], ARGV[3])
+"#;
+
+/// Sets the fields given as pairs after the TTL and returns the fields the map had before.
+const MERGE_MAP_SCRIPT: &str = r#"
+local previous = redis.call('HGETALL', KEYS[1])
+if #ARGV > 1 then
+    redis.call('HSET', KEYS[1], unpack(ARGV, 2))
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+return previous
+"#;
+
+/// Moves the due members to the given score and returns them.
+const CLAIM_SORTED_SET_MEMBERS_SCRIPT: &str = r#"
+local members = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+for _, member in ipairs(members) do
+    redis.call('ZADD', KEYS[1], ARGV[3], member)
+end
+return members
+"#;
+
+#[derive(Clone)]
 pub struct RedisKvStore {
-    connection: redis::aio::ConnectionManager,
+    connection: Connection,
+}
+
+/// Either a connection to a single node, to the master found via sentinel, or to a cluster which
+/// routes every command to the node owning the slot of its key.
+#[derive(Clone)]
+pub(crate) enum Connection {
+    Single(Box<redis::aio::ConnectionManager>),
+    Sentinel(SentinelConnection),
+    Cluster(redis::cluster_async::ClusterConnection),
+}
+
+impl redis::aio::ConnectionLike for Connection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        match self {
+            Connection::Single(connection) => connection.req_packed_command(cmd),
+            Connection::Sentinel(connection) => connection.req_packed_command(cmd),
+            Connection::Cluster(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            Connection::Single(connection) => connection.req_packed_commands(cmd, offset, count),
+            Connection::Sentinel(connection) => connection.req_packed_commands(cmd, offset, count),
+            Connection::Cluster(connection) => connection.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Single(connection) => connection.get_db(),
+            Connection::Sentinel(connection) => connection.get_db(),
+            Connection::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
+
+/// Connection to the master found via sentinel. The connection manager only reconnects to the
+/// address it was created with, so the master is looked up again when it's unreachable or was
+/// demoted to a replica by a failover. Clones share the connection.
+#[derive(Clone)]
+pub(crate) struct SentinelConnection {
+    sentinel: Arc<Mutex<redis::sentinel::Sentinel>>,
+    master_name: String,
+    node_connection_info: redis::sentinel::SentinelNodeConnectionInfo,
+    connection: Arc<RwLock<redis::aio::ConnectionManager>>,
+}
+
+impl SentinelConnection {
+    async fn new(
+        sentinel: Arc<Mutex<redis::sentinel::Sentinel>>,
+        master_name: String,
+        node_connection_info: redis::sentinel::SentinelNodeConnectionInfo,
+    ) -> redis::RedisResult<Self> {
+        let connection = master(&sentinel, &master_name, &node_connection_info).await?;
+        Ok(Self {
+            sentinel,
+            master_name,
+            node_connection_info,
+            connection: Arc::new(RwLock::new(connection)),
+        })
+    }
+
+    fn current(&self) -> redis::aio::ConnectionManager {
+        self.connection.read().unwrap_or_else(|error| error.into_inner()).clone()
+    }
+
+    /// Looks up the master again if the error hints at a failover. The failed command isn't
+    /// retried, as it might have been applied.
+    async fn recover(&self, error: &redis::RedisError) {
+        let failover = error.kind() == redis::ErrorKind::ReadOnly
+            || error.is_io_error()
+            || error.is_connection_refusal()
+            || error.is_connection_dropped();
+        if !failover {
+            return;
+        }
+
+        match master(&self.sentinel, &self.master_name, &self.node_connection_info).await {
+            Ok(connection) => {
+                *self.connection.write().unwrap_or_else(|error| error.into_inner()) = connection;
+                tracing::info! { event = "redis_master_reconnected", master = self.master_name };
+            }
+            Err(master_error) => {
+                tracing::warn! {
+                    event = "redis_master_lookup_failed",
+                    master = self.master_name,
+                    error = ?master_error,
+                };
+            }
+        }
+    }
+}
+
+impl redis::aio::ConnectionLike for SentinelConnection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        Box::pin(async move {
+            let mut connection = self.current();
+            let result = connection.req_packed_command(cmd).await;
+            if let Err(error) = &result {
+                self.recover(error).await;
+            }
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        Box::pin(async move {
+            let mut connection = self.current();
+            let result = connection.req_packed_commands(cmd, offset, count).await;
+            if let Err(error) = &result {
+                self.recover(error).await;
+            }
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.current().get_db()
+    }
+}
+
+async fn master(
+    sentinel: &Mutex<redis::sentinel::Sentinel>,
+    master_name: &str,
+    node_connection_info: &redis::sentinel::SentinelNodeConnectionInfo,
+) -> redis::RedisResult<redis::aio::ConnectionManager> {
+    let client =
+        sentinel.lock().await.async_master_for(master_name, Some(node_connection_info)).await?;
+    client.get_connection_manager().await
 }
 
 #[async_trait]
@@ -20,12 +208,35 @@ impl KvStore for RedisKvStore {
         value: Vec<u8>,
         ttl_ms: u64,
     ) -> anyhow::Result<()> {
-        let _: () = self.connection.hset(&key, &map_key, value).await?;
-        let _: () = self.connection.pexpire(&key, ttl_ms.try_into()?).await?;
+        let _: () = redis::cmd("EVAL")
+            .arg(INSERT_MAP_KEY_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(&map_key)
+            .arg(value)
+            .arg(ttl_ms)
+            .query_async(&mut self.connection)
+            .await?;
         tracing::debug! { event = "map_key_inserted", key, map_key, ttl_ms };
         Ok(())
     }
 
+    async fn merge_map(
+        &mut self,
+        key: String,
+        values: HashMap<String, Vec<u8>>,
+        ttl_ms: u64,
+    ) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        let mut command = redis::cmd("EVAL");
+        command.arg(MERGE_MAP_SCRIPT).arg(1).arg(&key).arg(ttl_ms);
+        for (map_key, value) in values {
+            command.arg(map_key).arg(value);
+        }
+        let previous: HashMap<String, Vec<u8>> = command.query_async(&mut self.connection).await?;
+        tracing::debug! { event = "map_merged", key, ttl_ms };
+        Ok(previous)
+    }
+
     async fn get_map(&mut self, key: String) -> anyhow::Result<HashMap<String, Vec<u8>>> {
         let map: HashMap<String, Vec<u8>> = self.connection.hgetall(key).await?;
         Ok(map)
@@ -42,59 +253,344 @@ impl KvStore for RedisKvStore {
         tracing::debug! { event = "map_deleted", key };
         Ok(())
     }
+
+    async fn insert_sorted_set_member(
+        &mut self,
+        key: String,
+        member: Vec<u8>,
+        score: u64,
+    ) -> anyhow::Result<()> {
+        let _: () = self.connection.zadd(&key, member, score).await?;
+        tracing::debug! { event = "sorted_set_member_inserted", key, score };
+        Ok(())
+    }
+
+    async fn get_sorted_set_range(
+        &mut self,
+        key: String,
+        max_score: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let members: Vec<Vec<u8>> = self
+            .connection
+            .zrangebyscore_limit(&key, "-inf", max_score, 0, limit.try_into()?)
+            .await?;
+        Ok(members)
+    }
+
+    async fn claim_sorted_set_members(
+        &mut self,
+        key: String,
+        max_score: u64,
+        limit: usize,
+        score: u64,
+    ) -> anyhow::Result<Vec<Vec<u8>>> {
+        let members: Vec<Vec<u8>> = redis::cmd("EVAL")
+            .arg(CLAIM_SORTED_SET_MEMBERS_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(max_score)
+            .arg(limit)
+            .arg(score)
+            .query_async(&mut self.connection)
+            .await?;
+        tracing::debug! { event = "sorted_set_members_claimed", key, count = members.len() };
+        Ok(members)
+    }
+
+    async fn delete_sorted_set_member(
+        &mut self,
+        key: String,
+        member: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let deleted: u64 = self.connection.zrem(&key, member).await?;
+        tracing::debug! { event = "sorted_set_member_deleted", key, deleted };
+        Ok(deleted > 0)
+    }
+
+    async fn get_sorted_set_len(&mut self, key: String) -> anyhow::Result<u64> {
+        let len: u64 = self.connection.zcard(&key).await?;
+        Ok(len)
+    }
+
+    async fn acquire_lease(
+        &mut self,
+        key: String,
+        owner: String,
+        ttl_ms: u64,
+    ) -> anyhow::Result<bool> {
+        let acquired: bool = redis::cmd("EVAL")
+            .arg(ACQUIRE_LEASE_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(&owner)
+            .arg(ttl_ms)
+            .query_async(&mut self.connection)
+            .await?;
+        tracing::trace! { event = "lease_requested", key, owner, acquired };
+        Ok(acquired)
+    }
+
+    fn clone_box(&self) -> Box<dyn KvStore> {
+        Box::new(self.clone())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct Configuration {
-    host: String,
-    port: u16,
+    #[serde(default)]
+    mode: Mode,
+    /// Only used in standalone mode.
+    host: Option<String>,
+    /// Only used in standalone mode.
+    port: Option<u16>,
+    /// Seed nodes in cluster mode, sentinels in sentinel mode, as `host:port`.
+    #[serde(default)]
+    nodes: Vec<String>,
+    /// Name of the master to ask the sentinels for.
+    sentinel_master_name: Option<String>,
+    /// Credentials for the sentinels, `username` and `password` apply to the master.
+    sentinel_username: Option<String>,
+    sentinel_password: Option<String>,
     tls_enabled: bool,
+    #[serde(default)]
+    tls: TlsConfiguration,
     username: Option<String>,
     password: Option<String>,
     db: Option<i64>,
 }
 
+#[derive(Debug, Clone, Deserialize, Default)]
+struct TlsConfiguration {
+    /// PEM bundle of CAs to trust instead of the system trust store.
+    ca_cert_path: Option<String>,
+    /// PEM client certificate for mTLS, requires `client_key_path`.
+    client_cert_path: Option<String>,
+    /// PEM private key of the client certificate.
+    client_key_path: Option<String>,
+    /// Skips the verification of the server certificate. Only meant for development.
+    #[serde(default)]
+    insecure: bool,
+}
+
+impl TlsConfiguration {
+    fn mode(&self) -> redis::TlsMode {
+        match self.insecure {
+            true => redis::TlsMode::Insecure,
+            false => redis::TlsMode::Secure,
+        }
+    }
+
+    /// Loads the configured certificates. Returns `None` when none are configured, in which case
+    /// the system trust store is used.
+    fn certificates(&self) -> anyhow::Result<Option<redis::TlsCertificates>> {
+        let root_cert = match &self.ca_cert_path {
+            Some(path) => Some(read_pem(path)?),
+            None => None,
+        };
+        let client_tls = match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(redis::ClientTlsConfig {
+                client_cert: read_pem(cert_path)?,
+                client_key: read_pem(key_path)?,
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "kv_store.redis.tls.client_cert_path and client_key_path must be set together"
+            ),
+        };
+
+        if root_cert.is_none() && client_tls.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(redis::TlsCertificates { client_tls, root_cert }))
+    }
+}
+
+fn read_pem(path: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|error| anyhow::anyhow!("failed to read '{path}': {error}"))
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+enum Mode {
+    #[default]
+    #[serde(rename = "standalone")]
+    Standalone,
+    #[serde(rename = "cluster")]
+    Cluster,
+    #[serde(rename = "sentinel")]
+    Sentinel,
+}
+
+#[derive(Clone)]
+enum Client {
+    Standalone(redis::Client),
+    Cluster(redis::cluster::ClusterClient),
+    Sentinel {
+        // Keeps connections to the sentinels, so it can't be cloned.
+        sentinel: Arc<Mutex<redis::sentinel::Sentinel>>,
+        master_name: String,
+        node_connection_info: redis::sentinel::SentinelNodeConnectionInfo,
+    },
+}
+
 #[derive(Clone)]
 pub struct RedisKvStoreFactory {
-    client: redis::Client,
+    client: Client,
 }
 
 impl RedisKvStoreFactory {
     pub async fn new(config: &Config) -> anyhow::Result<Self> {
         let configuration = config.get::<Configuration>("kv_store.redis")?;
-        let addr = if configuration.tls_enabled {
-            redis::ConnectionAddr::TcpTls {
-                host: configuration.host,
-                port: configuration.port,
-                insecure: false,
-                tls_params: None, // TODO
-            }
-        } else {
-            redis::ConnectionAddr::Tcp(configuration.host, configuration.port)
+        let certificates = match configuration.tls_enabled {
+            true => configuration.tls.certificates()?,
+            false => None,
         };
         let redis = redis::RedisConnectionInfo {
             db: configuration.db.unwrap_or(0),
-            username: configuration.username,
-            password: configuration.password,
+            username: configuration.username.clone(),
+            password: configuration.password.clone(),
             protocol: redis::ProtocolVersion::RESP3,
         };
-        let mut client = redis::Client::open(redis::ConnectionInfo { addr, redis })?;
 
-        match client.check_connection() {
-            true => tracing::info! { event = "connected" },
-            false => tracing::warn! { event = "disconnected" },
-        }
+        let client = match configuration.mode {
+            Mode::Standalone => {
+                let (Some(host), Some(port)) = (configuration.host.clone(), configuration.port)
+                else {
+                    anyhow::bail!("kv_store.redis.host and kv_store.redis.port are required");
+                };
+                let connection_info = redis::ConnectionInfo {
+                    addr: connection_addr(&configuration, host, port),
+                    redis,
+                };
+                let mut client = match certificates {
+                    Some(certificates) => {
+                        redis::Client::build_with_tls(connection_info, certificates)?
+                    }
+                    None => redis::Client::open(connection_info)?,
+                };
+                match client.check_connection() {
+                    true => tracing::info! { event = "connected" },
+                    false => tracing::warn! { event = "disconnected" },
+                }
+                Client::Standalone(client)
+            }
+            Mode::Cluster => {
+                if redis.db != 0 {
+                    anyhow::bail!("kv_store.redis.db is not supported in cluster mode");
+                }
+                let mut builder = redis::cluster::ClusterClient::builder(
+                    nodes(&configuration)?
+                        .into_iter()
+                        .map(|addr| redis::ConnectionInfo { addr, redis: redis.clone() }),
+                )
+                .use_protocol(redis::ProtocolVersion::RESP3);
+                if let Some(username) = configuration.username.clone() {
+                    builder = builder.username(username);
+                }
+                if let Some(password) = configuration.password.clone() {
+                    builder = builder.password(password);
+                }
+                if configuration.tls_enabled {
+                    builder = builder.tls(configuration.tls.mode());
+                }
+                if let Some(certificates) = certificates {
+                    builder = builder.certs(certificates);
+                }
+                Client::Cluster(builder.build()?)
+            }
+            Mode::Sentinel => {
+                // The redis client only supports the TLS mode for the nodes found via sentinel.
+                if certificates.is_some() {
+                    anyhow::bail!(
+                        "kv_store.redis.tls certificates are not supported in sentinel mode"
+                    );
+                }
+                let Some(master_name) = configuration.sentinel_master_name.clone() else {
+                    anyhow::bail!(
+                        "kv_store.redis.sentinel_master_name is required in sentinel mode"
+                    );
+                };
+                let sentinel_redis = redis::RedisConnectionInfo {
+                    username: configuration.sentinel_username.clone(),
+                    password: configuration.sentinel_password.clone(),
+                    ..Default::default()
+                };
+                let sentinels = nodes(&configuration)?
+                    .into_iter()
+                    .map(|addr| redis::ConnectionInfo { addr, redis: sentinel_redis.clone() })
+                    .collect();
+                let node_connection_info = redis::sentinel::SentinelNodeConnectionInfo {
+                    tls_mode: configuration.tls_enabled.then(|| configuration.tls.mode()),
+                    redis_connection_info: Some(redis),
+                };
+                Client::Sentinel {
+                    sentinel: Arc::new(Mutex::new(redis::sentinel::Sentinel::build(sentinels)?)),
+                    master_name,
+                    node_connection_info,
+                }
+            }
+        };
 
         Ok(Self { client })
     }
 
-    async fn spawn_connection(&self) -> anyhow::Result<redis::aio::ConnectionManager> {
-        let connection = self.client.get_connection_manager().await?;
+    /// Opens a new connection, also used by other adapters sharing the redis of the KV store.
+    pub(crate) async fn spawn_connection(&self) -> anyhow::Result<Connection> {
+        let connection = match &self.client {
+            Client::Standalone(client) => {
+                Connection::Single(Box::new(client.get_connection_manager().await?))
+            }
+            Client::Cluster(client) => Connection::Cluster(client.get_async_connection().await?),
+            Client::Sentinel { sentinel, master_name, node_connection_info } => {
+                Connection::Sentinel(
+                    SentinelConnection::new(
+                        sentinel.clone(),
+                        master_name.clone(),
+                        node_connection_info.clone(),
+                    )
+                    .await?,
+                )
+            }
+        };
         tracing::info! { event = "connection_spawned" };
         Ok(connection)
     }
 }
 
+fn connection_addr(
+    configuration: &Configuration,
+    host: String,
+    port: u16,
+) -> redis::ConnectionAddr {
+    if configuration.tls_enabled {
+        redis::ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure: configuration.tls.insecure,
+            // Set by the client when certificates are configured.
+            tls_params: None,
+        }
+    } else {
+        redis::ConnectionAddr::Tcp(host, port)
+    }
+}
+
+fn nodes(configuration: &Configuration) -> anyhow::Result<Vec<redis::ConnectionAddr>> {
+    if configuration.nodes.is_empty() {
+        anyhow::bail!("kv_store.redis.nodes is required in cluster and sentinel mode");
+    }
+    configuration
+        .nodes
+        .iter()
+        .map(|node| {
+            let (host, port) = node.rsplit_once(':').ok_or_else(|| {
+                anyhow::anyhow!("invalid redis node '{node}', expected host:port")
+            })?;
+            Ok(connection_addr(configuration, host.to_string(), port.parse()?))
+        })
+        .collect()
+}
+
 #[async_trait]
 impl KvStoreFactory for RedisKvStoreFactory {
     async fn create(&self) -> anyhow::Result<Box<dyn KvStore>> {
@@ -104,6 +600,6 @@ impl KvStoreFactory for RedisKvStoreFactory {
     }
 
     fn clone_box(&self) -> Box<dyn KvStoreFactory> {
-        Box::new(Self { client: self.client.clone() })
+        Box::new(self.clone())
     }
 }
@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing, Router,
+};
+use config::Config;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::{mpsc, OnceCell};
+
+use crate::ports::message_consumer::{
+    MessageConsumer, MessageConsumerFactory, OffsetReset, RawMessage, ReplayConsumer,
+};
+
+/// Header holding the key of an event.
+const KEY_HEADER: &str = "x-event-key";
+
+/// Receives events via `POST /events/{topic}` instead of a broker. Every listener gets a bounded
+/// queue; when the queue of a listener is full, e.g. because its mailboxes are full, requests are
+/// rejected with `429 Too Many Requests` until it caught up. Events are only kept in memory.
+pub struct HttpMessageConsumer {
+    registry: Registry,
+    sender: mpsc::Sender<RawMessage>,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<RawMessage>>,
+}
+
+#[async_trait]
+impl MessageConsumer for HttpMessageConsumer {
+    async fn subscribe(&mut self, topics: &[String]) -> anyhow::Result<()> {
+        let mut senders = self.registry.senders.write().unwrap_or_else(PoisonError::into_inner);
+        for topic in topics {
+            senders.entry(topic.clone()).or_default().push(self.sender.clone());
+        }
+        Ok(())
+    }
+
+    async fn recv(&self) -> anyhow::Result<RawMessage> {
+        self.receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("http ingest endpoint stopped"))
+    }
+}
+
+/// Queues of the listeners by topic.
+#[derive(Clone, Default)]
+struct Registry {
+    senders: Arc<RwLock<HashMap<String, Vec<mpsc::Sender<RawMessage>>>>>,
+}
+
+#[derive(Clone)]
+struct Context {
+    registry: Registry,
+    tokens: Arc<Vec<String>>,
+    /// Number of the last received event, used as offset.
+    offset: Arc<AtomicI64>,
+}
+
+fn router(context: Context, max_body_bytes: usize) -> Router {
+    Router::new()
+        .route("/events/:topic", routing::post(post_event_handler))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .with_state(context)
+}
+
+async fn post_event_handler(
+    State(context): State<Context>,
+    Path(topic): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| is_allowed(&context.tokens, token)) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    // Senders are only ever added, so the map is consistent even if a writer panicked.
+    let senders = context
+        .registry
+        .senders
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&topic)
+        .cloned();
+    let Some(senders) = senders else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Reserves a slot in the queue of every listener first, so an event is either delivered to
+    // all of them or to none.
+    let mut permits = Vec::with_capacity(senders.len());
+    for sender in &senders {
+        match sender.try_reserve() {
+            Ok(permit) => permits.push(permit),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn! { event = "http_event_rejected", topic, reason = "queue_full" };
+                return (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "1")])
+                    .into_response();
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return StatusCode::SERVICE_UNAVAILABLE.into_response();
+            }
+        }
+    }
+
+    let message = RawMessage {
+        key: headers.get(KEY_HEADER).map(|value| value.as_bytes().to_vec()),
+        value: body.to_vec(),
+        topic,
+        headers: headers
+            .iter()
+            .filter(|(name, _)| *name != header::AUTHORIZATION && name.as_str() != KEY_HEADER)
+            .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+            .collect(),
+        partition: None,
+        offset: Some(context.offset.fetch_add(1, Ordering::Relaxed) + 1),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|now| now.as_millis() as i64),
+        ack_id: None,
+    };
+    for permit in permits {
+        permit.send(message.clone());
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// Compares the token with all allowed ones in constant time, so the comparison doesn't leak how
+/// much of a token matched.
+fn is_allowed(tokens: &[String], token: &str) -> bool {
+    let allowed = tokens.iter().fold(subtle::Choice::from(0), |allowed, candidate| {
+        allowed | candidate.as_bytes().ct_eq(token.as_bytes())
+    });
+    allowed.into()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Configuration {
+    hostname: String,
+    port: u16,
+    /// Accepted bearer tokens.
+    tokens: Vec<String>,
+    /// Max amount of events waiting per listener before requests are rejected.
+    #[serde(default = "default_queue_size")]
+    queue_size: usize,
+    #[serde(default = "default_max_body_bytes")]
+    max_body_bytes: usize,
+}
+
+fn default_queue_size() -> usize {
+    1000
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+#[derive(Clone)]
+pub struct HttpMessageConsumerFactory {
+    configuration: Configuration,
+    registry: Registry,
+    /// Started with the first consumer, so commands which don't consume don't bind the port.
+    server: Arc<OnceCell<()>>,
+}
+
+impl HttpMessageConsumerFactory {
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let configuration = config.get::<Configuration>("message_consumer.http")?;
+        if configuration.tokens.is_empty() {
+            anyhow::bail!("message_consumer.http.tokens must not be empty");
+        }
+
+        Ok(Self { configuration, registry: Registry::default(), server: Default::default() })
+    }
+
+    async fn start_server(&self) -> anyhow::Result<()> {
+        let hostname = self.configuration.hostname.clone();
+        let port = self.configuration.port;
+        let listener = tokio::net::TcpListener::bind((hostname.clone(), port)).await?;
+        let context = Context {
+            registry: self.registry.clone(),
+            tokens: Arc::new(self.configuration.tokens.clone()),
+            offset: Default::default(),
+        };
+        let app = router(context, self.configuration.max_body_bytes);
+
+        tokio::spawn(async move {
+            tracing::info! { event = "server_starting", hostname, port, path = "/events/{topic}" };
+            if let Err(error) = axum::serve(listener, app.into_make_service()).await {
+                tracing::error! { event = "server_crashed", ?error };
+            }
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageConsumerFactory for HttpMessageConsumerFactory {
+    async fn create(
+        &self,
+        _group_id: String,
+        // Events are only delivered to listeners which are running, there is nothing to reset.
+        _offset_reset: OffsetReset,
+    ) -> anyhow::Result<Box<dyn MessageConsumer>> {
+        self.server.get_or_try_init(|| self.start_server()).await?;
+
+        let (sender, receiver) = mpsc::channel(self.configuration.queue_size);
+        Ok(Box::new(HttpMessageConsumer {
+            registry: self.registry.clone(),
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+        }))
+    }
+
+    async fn create_replay(
+        &self,
+        _topics: &[String],
+        _from_ms: i64,
+        _to_ms: Option<i64>,
+    ) -> anyhow::Result<Box<dyn ReplayConsumer>> {
+        anyhow::bail!("replays are not supported by the http ingest endpoint, events aren't stored")
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageConsumerFactory> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn serve(queue_size: usize) -> (String, HttpMessageConsumer) {
+        let registry = Registry::default();
+        let (sender, receiver) = mpsc::channel(queue_size);
+        let mut consumer = HttpMessageConsumer {
+            registry: registry.clone(),
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+        };
+        consumer.subscribe(&["charging-sessions".to_string()]).await.unwrap();
+
+        let context = Context {
+            registry,
+            tokens: Arc::new(vec!["secret".to_string()]),
+            offset: Default::default(),
+        };
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router(context, 1024).into_make_service()).await.unwrap();
+        });
+        (format!("http://{address}/events"), consumer)
+    }
+
+    async fn post(url: &str, token: &str) -> StatusCode {
+        let response = reqwest::Client::new()
+            .post(url)
+            .bearer_auth(token)
+            .header(KEY_HEADER, "abc")
+            .body(r#"{"id": "abc"}"#)
+            .send()
+            .await
+            .unwrap();
+        StatusCode::from_u16(response.status().as_u16()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_event_is_received() {
+        let (url, consumer) = serve(10).await;
+
+        assert_eq!(post(&format!("{url}/charging-sessions"), "secret").await, StatusCode::ACCEPTED);
+
+        let message = consumer.recv().await.unwrap();
+        assert_eq!(message.topic, "charging-sessions");
+        assert_eq!(message.key, Some(b"abc".to_vec()));
+        assert_eq!(message.value, br#"{"id": "abc"}"#);
+        assert!(!message.headers.contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_rejected() {
+        let (url, _consumer) = serve(1).await;
+
+        assert_eq!(
+            post(&format!("{url}/charging-sessions"), "wrong").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(post(&format!("{url}/unknown"), "secret").await, StatusCode::NOT_FOUND);
+        assert_eq!(post(&format!("{url}/charging-sessions"), "secret").await, StatusCode::ACCEPTED);
+        // Nobody consumes the queue, so it's full now.
+        assert_eq!(
+            post(&format!("{url}/charging-sessions"), "secret").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
@@ -1,16 +1,28 @@
+use std::{collections::HashMap, time::Duration};
+
 use async_trait::async_trait;
 use config::Config;
 use rdkafka::{
     admin,
     client::DefaultClientContext,
-    consumer::{Consumer, StreamConsumer},
-    Message,
+    consumer::{BaseConsumer, Consumer, StreamConsumer},
+    message::{BorrowedMessage, Headers},
+    Message, Offset, TopicPartitionList,
 };
 use serde::Deserialize;
 
 type AdminClient = admin::AdminClient<DefaultClientContext>;
 
-use crate::ports::message_consumer::{MessageConsumer, MessageConsumerFactory, RawMessage};
+use crate::ports::message_consumer::{
+    MessageConsumer, MessageConsumerFactory, OffsetReset, RawMessage, ReplayConsumer,
+};
+
+/// Timeout of requests to the brokers when setting up a replay.
+const REPLAY_SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time without messages after which a replay checks the positions of its partitions. The last
+/// offset before the end of a partition might never be received, e.g. if it's a transaction
+/// marker or was compacted.
+const REPLAY_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct KafkaMessageConsumer {
     consumer: rdkafka::consumer::StreamConsumer,
@@ -20,7 +32,7 @@ pub struct KafkaMessageConsumer {
 
 #[async_trait]
 impl MessageConsumer for KafkaMessageConsumer {
-    async fn subscribe(&mut self, topics: &Vec<String>) -> anyhow::Result<()> {
+    async fn subscribe(&mut self, topics: &[String]) -> anyhow::Result<()> {
         #[cfg(feature = "create-kafka-topics")]
         {
             let new_topics: Vec<admin::NewTopic> = topics
@@ -39,10 +51,79 @@ impl MessageConsumer for KafkaMessageConsumer {
 
     async fn recv(&self) -> anyhow::Result<RawMessage> {
         let message = self.consumer.recv().await?;
-        let key = message.key().map(|key| key.to_vec());
-        let value = message.payload().unwrap_or_default().to_vec();
-        let topic = message.topic().to_string();
-        Ok(RawMessage { key, value, topic })
+        Ok(raw_message(&message))
+    }
+}
+
+fn raw_message(message: &BorrowedMessage<'_>) -> RawMessage {
+    let key = message.key().map(|key| key.to_vec());
+    let value = message.payload().unwrap_or_default().to_vec();
+    let topic = message.topic().to_string();
+    let headers = message
+        .headers()
+        .map(|headers| {
+            headers
+                .iter()
+                .filter_map(|header| Some((header.key.to_string(), header.value?.to_vec())))
+                .collect()
+        })
+        .unwrap_or_default();
+    RawMessage {
+        key,
+        value,
+        topic,
+        headers,
+        partition: Some(message.partition()),
+        offset: Some(message.offset()),
+        timestamp: message.timestamp().to_millis(),
+        ack_id: None,
+    }
+}
+
+pub struct KafkaReplayConsumer {
+    consumer: StreamConsumer,
+    /// Offset (exclusive) up to which each partition still needs to be read.
+    end_offsets: HashMap<(String, i32), i64>,
+}
+
+#[async_trait]
+impl ReplayConsumer for KafkaReplayConsumer {
+    async fn next(&mut self) -> anyhow::Result<Option<RawMessage>> {
+        while !self.end_offsets.is_empty() {
+            let message =
+                match tokio::time::timeout(REPLAY_IDLE_TIMEOUT, self.consumer.recv()).await {
+                    Ok(message) => message?,
+                    Err(_) => {
+                        self.remove_finished_partitions()?;
+                        continue;
+                    }
+                };
+            let partition = (message.topic().to_string(), message.partition());
+            let Some(end_offset) = self.end_offsets.get(&partition).copied() else {
+                continue;
+            };
+            if message.offset() + 1 >= end_offset {
+                self.end_offsets.remove(&partition);
+            }
+            if message.offset() < end_offset {
+                return Ok(Some(raw_message(&message)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl KafkaReplayConsumer {
+    /// Removes the partitions whose position reached the end offset without receiving it.
+    fn remove_finished_partitions(&mut self) -> anyhow::Result<()> {
+        let positions = self.consumer.position()?;
+        self.end_offsets.retain(|(topic, partition), end_offset| {
+            let position = positions
+                .find_partition(topic, *partition)
+                .and_then(|element| element.offset().to_raw());
+            position.is_none_or(|position| position < *end_offset)
+        });
+        Ok(())
     }
 }
 
@@ -55,8 +136,38 @@ struct Configuration {
     sasl_password: Option<String>,
     session_timeout_ms: u64,
     heartbeat_interval_ms: u64,
+    #[serde(default)]
+    ssl: SslConfiguration,
+    oauthbearer: Option<OauthbearerConfiguration>,
+    /// Passed to librdkafka as they are, after all other settings.
+    #[serde(default)]
+    extra_properties: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+struct SslConfiguration {
+    ca_location: Option<String>,
+    certificate_location: Option<String>,
+    key_location: Option<String>,
+    key_password: Option<String>,
+    /// Set to `none` to skip the hostname verification of the broker certificates.
+    endpoint_identification_algorithm: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OauthbearerConfiguration {
+    /// Only `oidc` is supported, as there is no way to provide tokens otherwise.
+    method: String,
+    client_id: String,
+    client_secret: String,
+    token_endpoint_url: String,
+    scope: Option<String>,
+    extensions: Option<String>,
 }
 
+/// Properties which are managed by Pathfinder and can't be overridden by `extra_properties`.
+const MANAGED_PROPERTIES: [&str; 2] = ["group.id", "enable.auto.commit"];
+
 #[derive(Clone)]
 pub struct KafkaMessageConsumerFactory {
     client: rdkafka::ClientConfig,
@@ -86,15 +197,66 @@ impl KafkaMessageConsumerFactory {
             client_config.set("sasl.password", username);
         }
 
+        for (key, value) in [
+            ("ssl.ca.location", configuration.ssl.ca_location),
+            ("ssl.certificate.location", configuration.ssl.certificate_location),
+            ("ssl.key.location", configuration.ssl.key_location),
+            ("ssl.key.password", configuration.ssl.key_password),
+            (
+                "ssl.endpoint.identification.algorithm",
+                configuration.ssl.endpoint_identification_algorithm,
+            ),
+        ] {
+            if let Some(value) = value {
+                client_config.set(key, value);
+            }
+        }
+
+        if let Some(oauthbearer) = configuration.oauthbearer {
+            client_config
+                .set("sasl.oauthbearer.method", oauthbearer.method)
+                .set("sasl.oauthbearer.client.id", oauthbearer.client_id)
+                .set("sasl.oauthbearer.client.secret", oauthbearer.client_secret)
+                .set("sasl.oauthbearer.token.endpoint.url", oauthbearer.token_endpoint_url);
+            if let Some(scope) = oauthbearer.scope {
+                client_config.set("sasl.oauthbearer.scope", scope);
+            }
+            if let Some(extensions) = oauthbearer.extensions {
+                client_config.set("sasl.oauthbearer.extensions", extensions);
+            }
+        }
+
+        for (key, value) in configuration.extra_properties {
+            if MANAGED_PROPERTIES.contains(&key.as_str()) {
+                anyhow::bail!("message_consumer.kafka.extra_properties can't set '{key}'");
+            }
+            client_config.set(key, value);
+        }
+
+        // librdkafka validates all properties (unknown keys, invalid values, missing files) when
+        // a client is created, so we fail on startup instead of once the first listener starts.
+        client_config
+            .create::<BaseConsumer>()
+            .map_err(|error| anyhow::anyhow!("invalid kafka configuration: {error}"))?;
+
         Ok(Self { client: client_config })
     }
 
-    async fn spawn(&self, group_id: String) -> anyhow::Result<(StreamConsumer, AdminClient)> {
+    async fn spawn(
+        &self,
+        group_id: String,
+        offset_reset: OffsetReset,
+    ) -> anyhow::Result<(StreamConsumer, AdminClient)> {
         let mut config = self.client.clone();
-        config.set(
-            "group.id",
-            format!("{}-{}", config.get("group.id").unwrap_or_default(), group_id),
-        );
+        config
+            .set("group.id", format!("{}-{}", config.get("group.id").unwrap_or_default(), group_id))
+            .set(
+                "auto.offset.reset",
+                match offset_reset {
+                    OffsetReset::Earliest => "earliest",
+                    OffsetReset::Latest => "latest",
+                },
+            );
         let consumer: StreamConsumer = config.create()?;
         let admin_client: AdminClient = config.create()?;
         tracing::info! { event = "consumer_spawned" };
@@ -104,13 +266,144 @@ impl KafkaMessageConsumerFactory {
 
 #[async_trait]
 impl MessageConsumerFactory for KafkaMessageConsumerFactory {
-    async fn create(&self, group_id: String) -> anyhow::Result<Box<dyn MessageConsumer>> {
-        let (consumer, admin_client) = self.spawn(group_id).await?;
+    async fn create(
+        &self,
+        group_id: String,
+        offset_reset: OffsetReset,
+    ) -> anyhow::Result<Box<dyn MessageConsumer>> {
+        let (consumer, admin_client) = self.spawn(group_id, offset_reset).await?;
 
         Ok(Box::new(KafkaMessageConsumer { consumer, admin_client }))
     }
 
+    async fn create_replay(
+        &self,
+        topics: &[String],
+        from_ms: i64,
+        to_ms: Option<i64>,
+    ) -> anyhow::Result<Box<dyn ReplayConsumer>> {
+        // A group of its own which never commits, so the offsets of the listeners stay untouched.
+        let mut config = self.client.clone();
+        config
+            .set(
+                "group.id",
+                format!(
+                    "{}-replay-{}",
+                    config.get("group.id").unwrap_or_default(),
+                    uuid::Uuid::new_v4()
+                ),
+            )
+            .set("enable.auto.commit", "false");
+        let consumer: StreamConsumer = config.create()?;
+
+        let mut partitions = TopicPartitionList::new();
+        for topic in topics {
+            let metadata = consumer.fetch_metadata(Some(topic), REPLAY_SETUP_TIMEOUT)?;
+            for partition in metadata.topics().iter().flat_map(|topic| topic.partitions()) {
+                partitions.add_partition_offset(topic, partition.id(), Offset::Offset(from_ms))?;
+            }
+        }
+        let start_offsets = consumer.offsets_for_times(partitions.clone(), REPLAY_SETUP_TIMEOUT)?;
+
+        let end_offsets = match to_ms {
+            Some(to_ms) => {
+                // The first message after the window marks its end.
+                partitions.set_all_offsets(Offset::Offset(to_ms + 1))?;
+                Some(consumer.offsets_for_times(partitions, REPLAY_SETUP_TIMEOUT)?)
+            }
+            None => None,
+        };
+
+        let mut assignment = TopicPartitionList::new();
+        let mut replay_end_offsets = HashMap::new();
+        for element in start_offsets.elements() {
+            let (topic, partition) = (element.topic(), element.partition());
+            let (_, high_watermark) =
+                consumer.fetch_watermarks(topic, partition, REPLAY_SETUP_TIMEOUT)?;
+            let end_offset = end_offsets
+                .as_ref()
+                .and_then(|end_offsets| end_offsets.find_partition(topic, partition))
+                .and_then(|element| element.offset().to_raw())
+                .filter(|offset| *offset >= 0)
+                .unwrap_or(high_watermark);
+
+            // Offset::End means there is no message after `from_ms` in this partition.
+            let Offset::Offset(start_offset) = element.offset() else {
+                continue;
+            };
+            if start_offset >= end_offset {
+                continue;
+            }
+            assignment.add_partition_offset(topic, partition, Offset::Offset(start_offset))?;
+            replay_end_offsets.insert((topic.to_string(), partition), end_offset);
+            tracing::info! {
+                event = "replay_partition_assigned",
+                topic,
+                partition,
+                start_offset,
+                end_offset,
+            };
+        }
+        consumer.assign(&assignment)?;
+
+        Ok(Box::new(KafkaReplayConsumer { consumer, end_offsets: replay_end_offsets }))
+    }
+
     fn clone_box(&self) -> Box<dyn MessageConsumerFactory> {
         Box::new(Self { client: self.client.clone() })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra_properties: serde_json::Value) -> Config {
+        // JSON is valid YAML.
+        let config = serde_json::json!({
+            "service_name": "pathfinder",
+            "message_consumer": {
+                "kafka": {
+                    "brokers": "localhost:9092",
+                    "security_protocol": "plaintext",
+                    "sasl_mechanism": "plain",
+                    "session_timeout_ms": 10000,
+                    "heartbeat_interval_ms": 500,
+                    "extra_properties": extra_properties,
+                },
+            },
+        });
+        Config::builder()
+            .add_source(config::File::from_str(&config.to_string(), config::FileFormat::Yaml))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_extra_properties_are_applied() {
+        let factory =
+            KafkaMessageConsumerFactory::new(&config(serde_json::json!({"fetch.min.bytes": "10"})))
+                .await
+                .unwrap();
+
+        assert_eq!(factory.client.get("fetch.min.bytes"), Some("10"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_extra_property_is_rejected() {
+        let result =
+            KafkaMessageConsumerFactory::new(&config(serde_json::json!({"no.such.property": "1"})))
+                .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_managed_property_is_rejected() {
+        let result =
+            KafkaMessageConsumerFactory::new(&config(serde_json::json!({"group.id": "other"})))
+                .await;
+
+        assert!(result.is_err());
+    }
+}
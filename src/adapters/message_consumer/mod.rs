@@ -1,11 +1,31 @@
 use serde::{Deserialize, Serialize};
 
+pub mod http;
 pub mod kafka;
+pub mod nats;
+pub mod postgres;
+pub mod rabbitmq;
+pub mod redis_streams;
+pub use http::HttpMessageConsumerFactory;
 pub use kafka::KafkaMessageConsumerFactory;
+pub use nats::NatsMessageConsumerFactory;
+pub use postgres::PostgresMessageConsumerFactory;
+pub use rabbitmq::RabbitMqMessageConsumerFactory;
+pub use redis_streams::RedisStreamsMessageConsumerFactory;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub enum MessageConsumerAdapter {
     #[serde(rename = "kafka")]
     #[default]
     Kafka,
+    #[serde(rename = "nats")]
+    Nats,
+    #[serde(rename = "redis_streams")]
+    RedisStreams,
+    #[serde(rename = "rabbitmq")]
+    RabbitMq,
+    #[serde(rename = "http")]
+    Http,
+    #[serde(rename = "postgres")]
+    Postgres,
 }
@@ -0,0 +1,348 @@
+use std::{collections::HashMap, sync::PoisonError, time::Duration};
+
+use async_nats::jetstream::{
+    self,
+    consumer::{pull, AckPolicy, DeliverPolicy},
+    message::{AckKind, Acker},
+};
+use async_trait::async_trait;
+use config::Config;
+use futures_util::StreamExt;
+use serde::Deserialize;
+
+use crate::ports::message_consumer::{
+    MessageConsumer, MessageConsumerFactory, OffsetReset, RawMessage, ReplayConsumer,
+};
+
+/// Time after which the server removes the ephemeral consumer of a replay once it's not used
+/// anymore.
+const REPLAY_INACTIVE_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// Consumes a JetStream stream with a durable pull consumer per listener. The subjects of the
+/// stream are the topics of the listener; messages are acked once the listeners processed them and
+/// redelivered by the server otherwise.
+pub struct NatsMessageConsumer {
+    stream: jetstream::stream::Stream,
+    durable_name: String,
+    deliver_policy: DeliverPolicy,
+    ack_wait: Duration,
+    max_deliver: i64,
+    nak_delay: Duration,
+    messages: tokio::sync::Mutex<Option<pull::Stream>>,
+    /// Ackers of the received messages and how often they were delivered, by their stream
+    /// sequence.
+    pending: std::sync::Mutex<HashMap<u64, (Acker, i64)>>,
+}
+
+#[async_trait]
+impl MessageConsumer for NatsMessageConsumer {
+    async fn subscribe(&mut self, topics: &[String]) -> anyhow::Result<()> {
+        // Creates the consumer or updates its subjects if it already exists.
+        let consumer = self
+            .stream
+            .create_consumer(pull::Config {
+                durable_name: Some(self.durable_name.clone()),
+                deliver_policy: self.deliver_policy,
+                ack_policy: AckPolicy::Explicit,
+                ack_wait: self.ack_wait,
+                max_deliver: self.max_deliver,
+                filter_subjects: topics.to_vec(),
+                ..Default::default()
+            })
+            .await?;
+        *self.messages.lock().await = Some(consumer.messages().await?);
+
+        tracing::info! {
+            event = "nats_consumer_subscribed",
+            durable_name = self.durable_name,
+            subjects = ?topics,
+        };
+        Ok(())
+    }
+
+    async fn recv(&self) -> anyhow::Result<RawMessage> {
+        let mut messages = self.messages.lock().await;
+        let Some(messages) = messages.as_mut() else {
+            anyhow::bail!("not subscribed to any subjects");
+        };
+        let Some(message) = messages.next().await else {
+            anyhow::bail!("message stream of consumer {} ended", self.durable_name);
+        };
+
+        let message = message?;
+        let raw_message = raw_message(&message)?;
+        let delivered = message.info().map_err(|error| anyhow::anyhow!(error))?.delivered;
+        let (_, acker) = message.split();
+        if let Some(offset) = raw_message.offset {
+            self.pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(offset as u64, (acker, delivered));
+        }
+        Ok(raw_message)
+    }
+
+    async fn ack(&self, message: &RawMessage) -> anyhow::Result<()> {
+        let Some(offset) = message.offset else {
+            return Ok(());
+        };
+        let pending =
+            self.pending.lock().unwrap_or_else(PoisonError::into_inner).remove(&(offset as u64));
+        if let Some((acker, _)) = pending {
+            acker.ack().await.map_err(|error| anyhow::anyhow!(error))?;
+        }
+        Ok(())
+    }
+
+    async fn nack(&self, message: &RawMessage) -> anyhow::Result<()> {
+        let Some(offset) = message.offset else {
+            return Ok(());
+        };
+        let pending =
+            self.pending.lock().unwrap_or_else(PoisonError::into_inner).remove(&(offset as u64));
+        let Some((acker, delivered)) = pending else {
+            return Ok(());
+        };
+        // The server stops redelivering after max_deliver anyway. Terminating the last delivery
+        // drops the message right away instead of after the ack wait.
+        let kind = if delivered >= self.max_deliver {
+            tracing::warn! {
+                event = "nats_message_terminated",
+                durable_name = self.durable_name,
+                subject = message.topic,
+                sequence = offset,
+                delivered,
+            };
+            AckKind::Term
+        } else {
+            AckKind::Nak(Some(self.nak_delay))
+        };
+        acker.ack_with(kind).await.map_err(|error| anyhow::anyhow!(error))?;
+        Ok(())
+    }
+}
+
+fn raw_message(message: &jetstream::Message) -> anyhow::Result<RawMessage> {
+    let info = message.info().map_err(|error| anyhow::anyhow!(error))?;
+    let headers = message
+        .headers
+        .as_ref()
+        .map(|headers| {
+            headers
+                .iter()
+                .filter_map(|(key, values)| {
+                    Some((key.to_string(), values.first()?.as_str().as_bytes().to_vec()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(RawMessage {
+        key: None,
+        value: message.payload.to_vec(),
+        topic: message.subject.to_string(),
+        headers,
+        partition: None,
+        offset: Some(info.stream_sequence as i64),
+        timestamp: Some((info.published.unix_timestamp_nanos() / 1_000_000) as i64),
+        ack_id: None,
+    })
+}
+
+pub struct NatsReplayConsumer {
+    messages: pull::Stream,
+    /// Amount of messages which were in the stream when the replay was created.
+    remaining: u64,
+    to_ms: Option<i64>,
+}
+
+#[async_trait]
+impl ReplayConsumer for NatsReplayConsumer {
+    async fn next(&mut self) -> anyhow::Result<Option<RawMessage>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let Some(message) = self.messages.next().await else {
+            return Ok(None);
+        };
+
+        self.remaining -= 1;
+        let message = raw_message(&message?)?;
+        if let (Some(to_ms), Some(timestamp)) = (self.to_ms, message.timestamp) {
+            if timestamp > to_ms {
+                self.remaining = 0;
+                return Ok(None);
+            }
+        }
+        Ok(Some(message))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Configuration {
+    url: String,
+    /// Name of the JetStream stream holding the subjects of all listeners.
+    stream: String,
+    credentials_file: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+    /// Time the server waits for an ack before redelivering a message.
+    #[serde(default = "default_ack_wait_ms")]
+    ack_wait_ms: u64,
+    /// Max amount of deliveries of a message which keeps failing. It's dropped afterwards.
+    #[serde(default = "default_max_deliver")]
+    max_deliver: i64,
+    /// Time the server waits before redelivering a message which failed.
+    #[serde(default = "default_nak_delay_ms")]
+    nak_delay_ms: u64,
+}
+
+fn default_ack_wait_ms() -> u64 {
+    30_000
+}
+
+fn default_max_deliver() -> i64 {
+    5
+}
+
+fn default_nak_delay_ms() -> u64 {
+    5_000
+}
+
+#[derive(Clone)]
+pub struct NatsMessageConsumerFactory {
+    context: jetstream::Context,
+    configuration: Configuration,
+}
+
+impl NatsMessageConsumerFactory {
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let configuration = config.get::<Configuration>("message_consumer.nats")?;
+        if configuration.max_deliver < 1 {
+            anyhow::bail!("message_consumer.nats.max_deliver must be at least 1");
+        }
+
+        let mut options =
+            async_nats::ConnectOptions::new().name(config.get_string("service_name")?);
+        if let Some(credentials_file) = &configuration.credentials_file {
+            options = options.credentials_file(credentials_file).await?;
+        }
+        if let (Some(username), Some(password)) = (&configuration.username, &configuration.password)
+        {
+            options = options.user_and_password(username.clone(), password.clone());
+        }
+        if let Some(token) = &configuration.token {
+            options = options.token(token.clone());
+        }
+        let client = options.connect(&configuration.url).await?;
+        let context = jetstream::new(client);
+
+        // Fails early if the stream doesn't exist.
+        context.get_stream(&configuration.stream).await?;
+
+        Ok(Self { context, configuration })
+    }
+}
+
+#[async_trait]
+impl MessageConsumerFactory for NatsMessageConsumerFactory {
+    async fn create(
+        &self,
+        group_id: String,
+        offset_reset: OffsetReset,
+    ) -> anyhow::Result<Box<dyn MessageConsumer>> {
+        let deliver_policy = match offset_reset {
+            OffsetReset::Earliest => DeliverPolicy::All,
+            OffsetReset::Latest => DeliverPolicy::New,
+        };
+        Ok(Box::new(NatsMessageConsumer {
+            stream: self.context.get_stream(&self.configuration.stream).await?,
+            durable_name: group_id,
+            deliver_policy,
+            ack_wait: Duration::from_millis(self.configuration.ack_wait_ms),
+            max_deliver: self.configuration.max_deliver,
+            nak_delay: Duration::from_millis(self.configuration.nak_delay_ms),
+            messages: tokio::sync::Mutex::new(None),
+            pending: std::sync::Mutex::new(HashMap::new()),
+        }))
+    }
+
+    async fn create_replay(
+        &self,
+        topics: &[String],
+        from_ms: i64,
+        to_ms: Option<i64>,
+    ) -> anyhow::Result<Box<dyn ReplayConsumer>> {
+        let start_time =
+            time::OffsetDateTime::from_unix_timestamp_nanos(from_ms as i128 * 1_000_000)?;
+        let stream = self.context.get_stream(&self.configuration.stream).await?;
+        let mut consumer = stream
+            .create_consumer(pull::Config {
+                deliver_policy: DeliverPolicy::ByStartTime { start_time },
+                ack_policy: AckPolicy::None,
+                filter_subjects: topics.to_vec(),
+                inactive_threshold: REPLAY_INACTIVE_THRESHOLD,
+                ..Default::default()
+            })
+            .await?;
+        let remaining = consumer.info().await?.num_pending;
+
+        Ok(Box::new(NatsReplayConsumer { messages: consumer.messages().await?, remaining, to_ms }))
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageConsumerFactory> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against a local nats-server with JetStream enabled, e.g. `nats-server -js`.
+    #[tokio::test]
+    #[ignore = "requires a local nats-server"]
+    async fn test_consume_and_ack() {
+        let url = std::env::var("NATS_URL").unwrap_or("localhost:4222".to_string());
+        let client = async_nats::connect(&url).await.unwrap();
+        let context = jetstream::new(client);
+        let stream_name = format!("pathfinder-test-{}", uuid::Uuid::new_v4());
+        let subject = format!("{stream_name}.charging-sessions");
+        context
+            .create_stream(jetstream::stream::Config {
+                name: stream_name.clone(),
+                subjects: vec![format!("{stream_name}.>")],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let config = Config::builder()
+            .add_source(config::File::from_str(
+                &serde_json::json!({
+                    "service_name": "pathfinder",
+                    "message_consumer": { "nats": { "url": url, "stream": stream_name } },
+                })
+                .to_string(),
+                config::FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap();
+        let factory = NatsMessageConsumerFactory::new(&config).await.unwrap();
+        let mut consumer = factory
+            .create("chargingsessionchanged".to_string(), OffsetReset::Earliest)
+            .await
+            .unwrap();
+        consumer.subscribe(std::slice::from_ref(&subject)).await.unwrap();
+
+        context.publish(subject.clone(), r#"{"id": "abc"}"#.into()).await.unwrap().await.unwrap();
+        let message = consumer.recv().await.unwrap();
+        consumer.ack(&message).await.unwrap();
+
+        assert_eq!(message.topic, subject);
+        assert_eq!(message.value, br#"{"id": "abc"}"#);
+        assert_eq!(message.offset, Some(1));
+
+        context.delete_stream(&stream_name).await.unwrap();
+    }
+}
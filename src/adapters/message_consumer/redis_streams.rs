@@ -0,0 +1,414 @@
+use std::collections::{HashMap, VecDeque};
+
+use async_trait::async_trait;
+use config::Config;
+use redis::{
+    streams::{
+        StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamRangeReply,
+        StreamReadOptions, StreamReadReply,
+    },
+    AsyncCommands,
+};
+use serde::Deserialize;
+
+use crate::{
+    adapters::kv_store::{redis::Connection, RedisKvStoreFactory},
+    ports::message_consumer::{
+        MessageConsumer, MessageConsumerFactory, OffsetReset, RawMessage, ReplayConsumer,
+    },
+};
+
+/// Consumes redis streams with a consumer group per listener, using the redis of the KV store.
+/// Every topic is a stream key. Entries are acked once the listeners processed them; entries which
+/// stay pending longer than `claim_idle_ms`, e.g. because they failed or their consumer died, are
+/// claimed again by a consumer of the group.
+///
+/// The id of an entry (`<ms>-<seq>`) is passed on as `ack_id` of the message and its time part as
+/// `timestamp`. Streams aren't partitioned, so there is no `offset`.
+///
+/// In a Redis Cluster, all streams of a consumer are read with a single command, so they have to
+/// share a slot, e.g. by a common hash tag like `{events}.charging-sessions`.
+pub struct RedisStreamsMessageConsumer {
+    /// Connection for everything but the blocking reads, so acks don't wait for them.
+    connection: Connection,
+    read_connection: Connection,
+    group: String,
+    consumer: String,
+    start_id: &'static str,
+    configuration: Configuration,
+    state: tokio::sync::Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    topics: Vec<String>,
+    buffer: VecDeque<RawMessage>,
+    last_claim: Option<tokio::time::Instant>,
+    /// Where the next claim of a stream continues, if the last one didn't get through all of its
+    /// pending entries.
+    claim_cursors: HashMap<String, String>,
+}
+
+#[async_trait]
+impl MessageConsumer for RedisStreamsMessageConsumer {
+    async fn subscribe(&mut self, topics: &[String]) -> anyhow::Result<()> {
+        if let Connection::Cluster(_) = self.connection {
+            if !share_slot(topics) {
+                anyhow::bail!(
+                    "streams {topics:?} of group {} are on different cluster slots, give them a \
+                    common hash tag like {{events}}.<name>",
+                    self.group
+                );
+            }
+        }
+        for topic in topics {
+            let result: redis::RedisResult<()> =
+                self.connection.xgroup_create_mkstream(topic, &self.group, self.start_id).await;
+            match result {
+                Err(error) if error.code() != Some("BUSYGROUP") => return Err(error.into()),
+                _ => {}
+            }
+        }
+        self.state.lock().await.topics = topics.to_vec();
+
+        tracing::info! {
+            event = "redis_streams_subscribed",
+            group = self.group,
+            consumer = self.consumer,
+            streams = ?topics,
+        };
+        Ok(())
+    }
+
+    async fn recv(&self) -> anyhow::Result<RawMessage> {
+        let mut state = self.state.lock().await;
+        let mut connection = self.connection.clone();
+        let mut read_connection = self.read_connection.clone();
+        loop {
+            if let Some(message) = state.buffer.pop_front() {
+                return Ok(message);
+            }
+            if state.topics.is_empty() {
+                anyhow::bail!("not subscribed to any streams");
+            }
+
+            let claim_interval =
+                tokio::time::Duration::from_millis(self.configuration.claim_interval_ms);
+            if state.last_claim.is_none_or(|last_claim| last_claim.elapsed() >= claim_interval) {
+                state.last_claim = Some(tokio::time::Instant::now());
+                for topic in state.topics.clone() {
+                    let cursor = state.claim_cursors.remove(&topic);
+                    let reply: StreamAutoClaimReply = connection
+                        .xautoclaim_options(
+                            &topic,
+                            &self.group,
+                            &self.consumer,
+                            self.configuration.claim_idle_ms,
+                            cursor.as_deref().unwrap_or("0-0"),
+                            StreamAutoClaimOptions::default().count(self.configuration.batch_size),
+                        )
+                        .await?;
+                    // Claims again once the claimed entries are handed out, until the cursor
+                    // wrapped around.
+                    if reply.next_stream_id != "0-0" {
+                        state.claim_cursors.insert(topic.clone(), reply.next_stream_id.clone());
+                        state.last_claim = None;
+                    }
+                    if !reply.claimed.is_empty() {
+                        tracing::info! {
+                            event = "redis_stream_entries_claimed",
+                            stream = topic,
+                            count = reply.claimed.len(),
+                        };
+                    }
+                    for entry in &reply.claimed {
+                        state.buffer.push_back(self.configuration.raw_message(&topic, entry)?);
+                    }
+                }
+                continue;
+            }
+
+            let options = StreamReadOptions::default()
+                .group(&self.group, &self.consumer)
+                .count(self.configuration.batch_size)
+                .block(self.configuration.block_ms);
+            let ids = vec![">"; state.topics.len()];
+            let reply: Option<StreamReadReply> =
+                read_connection.xread_options(&state.topics, &ids, &options).await?;
+            for stream in reply.map(|reply| reply.keys).unwrap_or_default() {
+                for entry in &stream.ids {
+                    state.buffer.push_back(self.configuration.raw_message(&stream.key, entry)?);
+                }
+            }
+        }
+    }
+
+    async fn ack(&self, message: &RawMessage) -> anyhow::Result<()> {
+        let Some(id) = &message.ack_id else {
+            return Ok(());
+        };
+        let mut connection = self.connection.clone();
+        let _: () = connection.xack(&message.topic, &self.group, &[id]).await?;
+        Ok(())
+    }
+
+    /// Nacked entries stay pending and are claimed again after `claim_idle_ms`.
+    async fn nack(&self, _message: &RawMessage) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether all streams are on the same slot of a Redis Cluster.
+fn share_slot(topics: &[String]) -> bool {
+    let slot = |topic: &String| redis::cluster_routing::get_slot(topic.as_bytes());
+    topics.iter().all(|topic| slot(topic) == slot(&topics[0]))
+}
+
+/// Splits an entry id into its timestamp (ms) and sequence number.
+fn parse_entry_id(id: &str) -> anyhow::Result<(i64, i64)> {
+    let (timestamp, sequence) =
+        id.split_once('-').ok_or_else(|| anyhow::anyhow!("invalid stream entry id '{id}'"))?;
+    Ok((timestamp.parse()?, sequence.parse()?))
+}
+
+pub struct RedisStreamsReplayConsumer {
+    connection: Connection,
+    cursors: Vec<ReplayCursor>,
+    /// Last entry id (inclusive) to read.
+    end: String,
+    configuration: Configuration,
+}
+
+/// Reading position within one stream of a replay.
+struct ReplayCursor {
+    topic: String,
+    /// Entry id to continue from, exclusive after the first read.
+    start: String,
+    buffer: VecDeque<RawMessage>,
+    exhausted: bool,
+}
+
+#[async_trait]
+impl ReplayConsumer for RedisStreamsReplayConsumer {
+    async fn next(&mut self) -> anyhow::Result<Option<RawMessage>> {
+        for cursor in &mut self.cursors {
+            if !cursor.buffer.is_empty() || cursor.exhausted {
+                continue;
+            }
+            let reply: StreamRangeReply = self
+                .connection
+                .xrange_count(
+                    &cursor.topic,
+                    &cursor.start,
+                    &self.end,
+                    self.configuration.batch_size,
+                )
+                .await?;
+            cursor.exhausted = reply.ids.len() < self.configuration.batch_size;
+            if let Some(last) = reply.ids.last() {
+                cursor.start = format!("({}", last.id);
+            }
+            for entry in &reply.ids {
+                cursor.buffer.push_back(self.configuration.raw_message(&cursor.topic, entry)?);
+            }
+        }
+
+        // Merges the streams in the order the entries were added.
+        let next = self.cursors.iter_mut().filter(|cursor| !cursor.buffer.is_empty()).min_by_key(
+            |cursor| cursor.buffer[0].ack_id.as_deref().and_then(|id| parse_entry_id(id).ok()),
+        );
+        Ok(next.and_then(|cursor| cursor.buffer.pop_front()))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct Configuration {
+    /// Field of an entry holding the message key. All fields besides key and value become headers.
+    key_field: String,
+    /// Field of an entry holding the message value.
+    value_field: String,
+    /// Max amount of entries read at once.
+    batch_size: usize,
+    /// Time a read waits for new entries.
+    block_ms: usize,
+    /// Time after which a pending entry of another consumer is claimed.
+    claim_idle_ms: u64,
+    /// Interval in which pending entries are checked for claiming.
+    claim_interval_ms: u64,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            key_field: "key".to_string(),
+            value_field: "value".to_string(),
+            batch_size: 100,
+            block_ms: 1000,
+            claim_idle_ms: 60_000,
+            claim_interval_ms: 10_000,
+        }
+    }
+}
+
+impl Configuration {
+    fn raw_message(&self, topic: &str, entry: &StreamId) -> anyhow::Result<RawMessage> {
+        let (timestamp, _) = parse_entry_id(&entry.id)?;
+        let mut message = RawMessage {
+            topic: topic.to_string(),
+            timestamp: Some(timestamp),
+            ack_id: Some(entry.id.clone()),
+            ..Default::default()
+        };
+        for (field, value) in &entry.map {
+            let value: Vec<u8> = redis::from_redis_value(value)?;
+            if *field == self.key_field {
+                message.key = Some(value);
+            } else if *field == self.value_field {
+                message.value = value;
+            } else {
+                message.headers.insert(field.clone(), value);
+            }
+        }
+        Ok(message)
+    }
+}
+
+#[derive(Clone)]
+pub struct RedisStreamsMessageConsumerFactory {
+    kv_store_factory: RedisKvStoreFactory,
+    service_name: String,
+    configuration: Configuration,
+}
+
+impl RedisStreamsMessageConsumerFactory {
+    pub async fn new(config: &Config) -> anyhow::Result<Self> {
+        let configuration =
+            config.get::<Configuration>("message_consumer.redis_streams").unwrap_or_default();
+
+        Ok(Self {
+            kv_store_factory: RedisKvStoreFactory::new(config).await?,
+            service_name: config.get_string("service_name")?,
+            configuration,
+        })
+    }
+}
+
+#[async_trait]
+impl MessageConsumerFactory for RedisStreamsMessageConsumerFactory {
+    async fn create(
+        &self,
+        group_id: String,
+        offset_reset: OffsetReset,
+    ) -> anyhow::Result<Box<dyn MessageConsumer>> {
+        let start_id = match offset_reset {
+            OffsetReset::Earliest => "0",
+            OffsetReset::Latest => "$",
+        };
+        // Every consumer gets its own connections, as reads block them.
+        Ok(Box::new(RedisStreamsMessageConsumer {
+            connection: self.kv_store_factory.spawn_connection().await?,
+            read_connection: self.kv_store_factory.spawn_connection().await?,
+            group: group_id,
+            consumer: format!("{}-{}", self.service_name, uuid::Uuid::new_v4()),
+            start_id,
+            configuration: self.configuration.clone(),
+            state: Default::default(),
+        }))
+    }
+
+    async fn create_replay(
+        &self,
+        topics: &[String],
+        from_ms: i64,
+        to_ms: Option<i64>,
+    ) -> anyhow::Result<Box<dyn ReplayConsumer>> {
+        // An id with only the timestamp covers all entries of that millisecond.
+        let end = match to_ms {
+            Some(to_ms) => to_ms.to_string(),
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_millis()
+                .to_string(),
+        };
+        let cursors = topics
+            .iter()
+            .map(|topic| ReplayCursor {
+                topic: topic.clone(),
+                start: from_ms.to_string(),
+                buffer: VecDeque::new(),
+                exhausted: false,
+            })
+            .collect();
+
+        Ok(Box::new(RedisStreamsReplayConsumer {
+            connection: self.kv_store_factory.spawn_connection().await?,
+            cursors,
+            end,
+            configuration: self.configuration.clone(),
+        }))
+    }
+
+    fn clone_box(&self) -> Box<dyn MessageConsumerFactory> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(value: &str) -> redis::Value {
+        redis::Value::BulkString(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_raw_message_from_entry() {
+        let entry = StreamId {
+            id: "1700000000000-3".to_string(),
+            map: [
+                ("key".to_string(), bulk("abc")),
+                ("value".to_string(), bulk(r#"{"id": "abc"}"#)),
+                ("source".to_string(), bulk("billing")),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        let message = Configuration::default().raw_message("charging-sessions", &entry).unwrap();
+
+        assert_eq!(message.key, Some(b"abc".to_vec()));
+        assert_eq!(message.value, br#"{"id": "abc"}"#);
+        assert_eq!(message.headers.get("source"), Some(&b"billing".to_vec()));
+        assert_eq!(message.timestamp, Some(1_700_000_000_000));
+        assert_eq!(message.ack_id.as_deref(), Some("1700000000000-3"));
+        assert_eq!(message.offset, None);
+    }
+
+    #[test]
+    fn test_share_slot() {
+        let topics =
+            |topics: &[&str]| topics.iter().map(|topic| topic.to_string()).collect::<Vec<_>>();
+
+        assert!(share_slot(&topics(&["{sessions}.started", "{sessions}.stopped"])));
+        assert!(!share_slot(&topics(&["sessions.started", "sessions.stopped"])));
+    }
+
+    #[test]
+    fn test_read_reply_in_resp3() {
+        // XREADGROUP replies with a map of streams in RESP3.
+        let value = redis::Value::Map(vec![(
+            bulk("charging-sessions"),
+            redis::Value::Array(vec![redis::Value::Array(vec![
+                bulk("1700000000000-0"),
+                redis::Value::Array(vec![bulk("value"), bulk("{}")]),
+            ])]),
+        )]);
+
+        let reply: StreamReadReply = redis::from_redis_value(&value).unwrap();
+
+        assert_eq!(reply.keys.len(), 1);
+        assert_eq!(reply.keys[0].key, "charging-sessions");
+        assert_eq!(reply.keys[0].ids[0].id, "1700000000000-0");
+    }
+}
@@ -2,7 +2,7 @@ use clap::Parser;
 use serde::Serialize;
 
 use crate::{
-    commands::{export_schema, listen, publish_schema},
+    commands::{export_schema, listen, publish_schema, replay},
     configuration::{self},
     tracing_guard::TracingGuard,
 };
@@ -25,6 +25,8 @@ enum Command {
     },
     ExportSchema(ExportSchemaArgs),
     PublishSchema,
+    /// Reprocesses the messages of a listener received in a time window.
+    Replay(ReplayArgs),
 }
 
 #[derive(Debug, Serialize, Parser)]
@@ -33,6 +35,19 @@ struct ExportSchemaArgs {
     path: String,
 }
 
+#[derive(Debug, Serialize, Parser)]
+struct ReplayArgs {
+    /// Operation of the listener to replay.
+    #[clap(short, long)]
+    listener: String,
+    /// Start of the window, as unix timestamp in milliseconds or RFC 3339.
+    #[clap(short, long)]
+    from: String,
+    /// End of the window (inclusive), defaults to the latest message.
+    #[clap(short, long)]
+    to: Option<String>,
+}
+
 pub async fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
     let config = configuration::build(cli.config_path).await?;
@@ -47,5 +62,6 @@ pub async fn run() -> anyhow::Result<()> {
         }
         Command::ExportSchema(args) => export_schema::run(&config, args.path).await,
         Command::PublishSchema => publish_schema::run(&config).await,
+        Command::Replay(args) => replay::run(&config, args.listener, args.from, args.to).await,
     }
 }
@@ -10,6 +10,28 @@ use crate::{
 };
 
 pub async fn run(config: &Config) -> anyhow::Result<()> {
+    let kv_store_factory = kv_store_factory(config).await?;
+    let message_consumer_factory = message_consumer_factory(config).await?;
+    let router_client = router_client(config)?;
+
+    let listener = listener::Listener::spawn(
+        config,
+        router_client,
+        kv_store_factory,
+        message_consumer_factory,
+    )
+    .await?;
+
+    let health_endpoint = health::HealthEndpoint::spawn(config, listener.clone()).await;
+
+    wait_for_terminate_signal().await?;
+    listener.stop_gracefully().await?;
+    health_endpoint.stop_gracefully().await?;
+
+    Ok(())
+}
+
+pub(crate) async fn kv_store_factory(config: &Config) -> anyhow::Result<Box<dyn KvStoreFactory>> {
     let kv_store_factory: Box<dyn KvStoreFactory> = match config
         .get::<adapters::kv_store::KvStoreAdapter>("kv_store.adapter")
         .unwrap_or_default()
@@ -21,7 +43,12 @@ pub async fn run(config: &Config) -> anyhow::Result<()> {
             Box::new(adapters::kv_store::RedisKvStoreFactory::new(config).await?)
         }
     };
+    Ok(kv_store_factory)
+}
 
+pub(crate) async fn message_consumer_factory(
+    config: &Config,
+) -> anyhow::Result<Box<dyn MessageConsumerFactory>> {
     let message_consumer_factory: Box<dyn MessageConsumerFactory> = match config
         .get::<adapters::message_consumer::MessageConsumerAdapter>("message_consumer.adapter")
         .unwrap_or_default()
@@ -29,8 +56,26 @@ pub async fn run(config: &Config) -> anyhow::Result<()> {
         adapters::message_consumer::MessageConsumerAdapter::Kafka => {
             Box::new(adapters::message_consumer::KafkaMessageConsumerFactory::new(config).await?)
         }
+        adapters::message_consumer::MessageConsumerAdapter::Nats => {
+            Box::new(adapters::message_consumer::NatsMessageConsumerFactory::new(config).await?)
+        }
+        adapters::message_consumer::MessageConsumerAdapter::Http => {
+            Box::new(adapters::message_consumer::HttpMessageConsumerFactory::new(config).await?)
+        }
+        adapters::message_consumer::MessageConsumerAdapter::Postgres => {
+            Box::new(adapters::message_consumer::PostgresMessageConsumerFactory::new(config).await?)
+        }
+        adapters::message_consumer::MessageConsumerAdapter::RabbitMq => {
+            Box::new(adapters::message_consumer::RabbitMqMessageConsumerFactory::new(config).await?)
+        }
+        adapters::message_consumer::MessageConsumerAdapter::RedisStreams => Box::new(
+            adapters::message_consumer::RedisStreamsMessageConsumerFactory::new(config).await?,
+        ),
     };
+    Ok(message_consumer_factory)
+}
 
+pub(crate) fn router_client(config: &Config) -> anyhow::Result<Box<dyn RouterClient>> {
     let router_client: Box<dyn RouterClient> = match config
         .get::<adapters::router_client::RouterClientAdapter>("router_client.adapter")
         .unwrap_or_default()
@@ -42,22 +87,7 @@ pub async fn run(config: &Config) -> anyhow::Result<()> {
             Box::new(adapters::router_client::InMemoryRouterClient::new())
         }
     };
-
-    let listener = listener::Listener::spawn(
-        config,
-        router_client,
-        kv_store_factory,
-        message_consumer_factory,
-    )
-    .await?;
-
-    let health_endpoint = health::HealthEndpoint::spawn(config, listener.clone()).await;
-
-    wait_for_terminate_signal().await?;
-    listener.stop_gracefully().await?;
-    health_endpoint.stop_gracefully().await?;
-
-    Ok(())
+    Ok(router_client)
 }
 
 async fn wait_for_terminate_signal() -> anyhow::Result<()> {
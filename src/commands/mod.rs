@@ -1,3 +1,4 @@
 pub mod export_schema;
 pub mod listen;
 pub mod publish_schema;
+pub mod replay;
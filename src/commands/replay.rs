@@ -0,0 +1,53 @@
+use config::Config;
+
+use crate::listener;
+
+use super::listen;
+
+pub async fn run(
+    config: &Config,
+    operation: String,
+    from: String,
+    to: Option<String>,
+) -> anyhow::Result<()> {
+    let from_ms = parse_timestamp(&from)?;
+    let to_ms = to.as_deref().map(parse_timestamp).transpose()?;
+
+    let count = listener::replay(
+        config,
+        &operation,
+        from_ms,
+        to_ms,
+        listen::router_client(config)?,
+        listen::kv_store_factory(config).await?,
+        listen::message_consumer_factory(config).await?,
+    )
+    .await?;
+    tracing::info! { event = "replay_finished", operation, from_ms, to_ms, count };
+
+    Ok(())
+}
+
+/// Accepts either unix timestamps in milliseconds or RFC 3339 timestamps.
+fn parse_timestamp(value: &str) -> anyhow::Result<i64> {
+    if let Ok(timestamp_ms) = value.parse::<i64>() {
+        return Ok(timestamp_ms);
+    }
+    let timestamp =
+        time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339)
+            .map_err(|error| anyhow::anyhow!("invalid timestamp '{value}': {error}"))?;
+    Ok((timestamp.unix_timestamp_nanos() / 1_000_000) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1718000000000").unwrap(), 1718000000000);
+        assert_eq!(parse_timestamp("2024-06-10T06:13:20Z").unwrap(), 1718000000000);
+        assert_eq!(parse_timestamp("2024-06-10T08:13:20.5+02:00").unwrap(), 1718000000500);
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+}
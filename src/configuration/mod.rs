@@ -26,31 +26,183 @@ pub struct Listener {
     pub description: Option<String>,
     /// The field name to use as the identifier for the entity.
     pub id_key: String,
+    /// Which subscriptions the updates of an entity are published to.
+    #[serde(default)]
+    pub subscribe_by: ListenerSubscribeBy,
     /// The max TTL for the subscription. When this time is over, all open subscriptions will be
     /// terminated.
     pub ttl_ms: u64,
     /// If enabled, publishes an initial update to the router when a new subscription is created.
-    /// Important: this only includes an object with the id_key set to the id of the entity. The
-    /// rest needs to be resolved by the router.
+    /// Important: unless `state_ttl_ms` is set, this only includes an object with the id_key set
+    /// to the id of the entity. The rest needs to be resolved by the router.
     #[serde(default)]
     pub publish_initial_update: bool,
+    /// If set, the last known state of every entity is kept in the KV store for this long, merged
+    /// from all of its events. The initial update then contains this state instead of the id only.
+    pub state_ttl_ms: Option<u64>,
+    /// If enabled, updates only contain the fields which changed compared to the last known state
+    /// of the entity, plus the id. Updates without changes aren't published at all. Requires
+    /// `state_ttl_ms`.
+    #[serde(default)]
+    pub publish_deltas: bool,
+    /// Where the consumer of this listener starts when it has no committed offsets yet, e.g. on
+    /// the first deployment.
+    #[serde(default = "ListenerOffsetReset::default")]
+    pub auto_offset_reset: ListenerOffsetReset,
+    /// Listeners with the same consumer group share one message consumer, so topics they have in
+    /// common are only consumed once. Sharing is opt-in: when not set, the listener gets its own
+    /// consumer group named after the operation, which keeps the offsets committed so far.
+    pub consumer_group: Option<String>,
+    /// If set, the entity is fetched from a GraphQL endpoint before an update is published and
+    /// merged into the payload.
+    pub enrichment: Option<ListenerEnrichment>,
+    /// Rules a subscription has to satisfy before it's stored. Requires
+    /// `router_endpoint.authorization`, as the rules are evaluated against the verified claims of
+    /// the forwarded token.
+    #[serde(default)]
+    pub authorization: Vec<ListenerAuthorizationRule>,
     /// The topics to listen for changes on.
     pub topics: Vec<Topic>,
 }
 pub type Listeners = Vec<Listener>;
 
+impl Listener {
+    /// Name of the argument of the subscription operation, if it takes one.
+    pub fn subscription_argument(&self) -> Option<&str> {
+        match &self.subscribe_by {
+            ListenerSubscribeBy::Id => Some(&self.id_key),
+            ListenerSubscribeBy::Field(field) => Some(field),
+            ListenerSubscribeBy::All => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum ListenerSubscribeBy {
+    /// Subscriptions take the id of an entity and get its updates.
+    #[default]
+    #[serde(rename = "id")]
+    Id,
+    /// Subscriptions take a value of this field, e.g. `accountId`, and get the updates of all
+    /// entities with that value.
+    #[serde(rename = "field")]
+    Field(String),
+    /// Subscriptions take no argument and get the updates of all entities.
+    #[serde(rename = "all")]
+    All,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ListenerAuthorizationRule {
+    /// Claim of the token, e.g. `sub`. If the claim is an array, one of its values has to match.
+    pub claim: String,
+    /// Field of the entity which has to equal the claim, e.g. `ownerId`. Taken from the
+    /// subscription argument if it's the same field, otherwise from the last known state of the
+    /// entity, which requires `state_ttl_ms`.
+    pub field: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ListenerEnrichment {
+    /// GraphQL endpoint resolving `_entities`, usually the subgraph owning the entity.
+    pub url: String,
+    /// Selection set fetched for the entity, e.g. `status energyKwh startedAt`.
+    pub selection: String,
+    /// Headers sent with every request, e.g. for authorization.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub timeout_ms: EnrichmentTimeout,
+    /// Time a fetched entity is reused for the same entity and event.
+    #[serde(default)]
+    pub cache_ttl_ms: EnrichmentCacheTtl,
+    /// Max amount of cached entities per processor.
+    #[serde(default)]
+    pub cache_size: EnrichmentCacheSize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
+pub struct EnrichmentTimeout(pub u64);
+impl Default for EnrichmentTimeout {
+    fn default() -> Self {
+        EnrichmentTimeout(2_000)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
+pub struct EnrichmentCacheTtl(pub u64);
+impl Default for EnrichmentCacheTtl {
+    fn default() -> Self {
+        EnrichmentCacheTtl(60_000)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
+pub struct EnrichmentCacheSize(pub usize);
+impl Default for EnrichmentCacheSize {
+    fn default() -> Self {
+        EnrichmentCacheSize(10_000)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum ListenerOffsetReset {
+    /// Starts with the oldest message which is still available.
+    #[serde(rename = "earliest")]
+    Earliest,
+    /// Only consumes messages which arrive after the consumer started.
+    #[default]
+    #[serde(rename = "latest")]
+    Latest,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Cluster {
+    /// If enabled, replicas coordinate through the KV store so periodic work (heartbeats, TTL
+    /// sweeps, delayed dispatches) of an operation is only done by the replica holding its lease.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Identifies this replica as lease owner. When not set, a random id is generated on start.
+    pub instance_id: Option<String>,
+    /// Time after which the lease of a replica expires if it doesn't renew it, e.g. because it
+    /// crashed. Another replica takes over afterwards.
+    #[serde(default)]
+    pub lease_ttl_ms: ClusterLeaseTtl,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
+pub struct ClusterLeaseTtl(pub u64);
+impl Default for ClusterLeaseTtl {
+    fn default() -> Self {
+        ClusterLeaseTtl(10_000)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Topic {
     /// The name of the topic.
     pub name: String,
-    /// Optional delay between receiving and notifying the router.
+    /// Optional delay between receiving and notifying the router. Delayed updates are kept in
+    /// the KV store.
     pub delay_ms: Option<u64>,
+    /// Optional debouncing of updates per entity. Can't be combined with `delay_ms`.
+    pub debounce: Option<TopicDebounce>,
     /// The source of the data to use for the topic.
     #[serde(default = "TopicDataSerde::default")]
     pub data_serde: TopicDataSerde,
     /// The source of the data to use for the topic.
     #[serde(default = "TopicDataSource::default")]
     pub data_source: TopicDataSource,
+    /// Decoding of the message key when data_source is key_and_value. The settings of the topic
+    /// itself apply to the value.
+    #[serde(default)]
+    pub key_decoding: TopicKeyDecoding,
+    /// Fields which are taken from message headers (field -> header), merged into the decoded
+    /// data. Header values are strings.
+    #[serde(default)]
+    pub header_mapping: HashMap<String, String>,
+    /// If set, the timestamp of the message (unix ms) is added to the data under this key.
+    pub timestamp_key: Option<String>,
     /// If enabled, strips out all not-declared fields from the incoming data.
     /// Only used when data_serde is set to json.
     #[serde(default)]
@@ -63,12 +215,34 @@ pub struct Topic {
     /// Only used when data_serde is set to json.
     #[serde(default)]
     pub json_mapping: JsonMapping,
+    /// If set, messages are Debezium change events and the changed row is unwrapped from their
+    /// envelope. Only used when data_serde is set to json.
+    pub debezium: Option<TopicDebezium>,
+    /// Decides whether a message on this topic is meant for this listener, for topics shared by
+    /// several listeners. All messages are processed when not set.
+    pub route: Option<TopicRoute>,
+    /// Only messages with one of these CloudEvents types are processed, others are skipped. Allows
+    /// listeners to share a topic carrying different types of events. Only used when data_serde is
+    /// set to cloudevents.
+    #[serde(default)]
+    pub event_types: Vec<String>,
     /// Whether the topic terminates subscriptions.
     /// If the manager receives a message on a topic that terminates subscriptions, it will
     /// terminate all subscriptions that are listening on this topic AFTER sending a final next
     /// message to the router.
     #[serde(default)]
     pub terminates_subscriptions: bool,
+    /// Amount of processors handling the messages of this topic in parallel. Messages are
+    /// distributed between them based on `partition_by`, so ordering is preserved per entity.
+    #[serde(default)]
+    pub processors: TopicProcessors,
+    /// Defines how messages are distributed between the processors of this topic.
+    #[serde(default)]
+    pub partition_by: TopicPartitionStrategy,
+    /// Max amount of concurrent requests to the router when dispatching an update to all
+    /// subscribers of an entity.
+    #[serde(default)]
+    pub dispatch_concurrency: TopicDispatchConcurrency,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -80,6 +254,9 @@ pub enum TopicDataSerde {
     #[default]
     #[serde(rename = "json")]
     Json,
+    /// CloudEvents with JSON data, in structured mode or in binary mode with `ce_` headers.
+    #[serde(rename = "cloudevents")]
+    CloudEvents,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -89,6 +266,111 @@ pub enum TopicDataSource {
     Key,
     #[serde(rename = "value")]
     Value,
+    /// Builds the data from the message headers, every header becomes a string field. Requires
+    /// data_serde to be json.
+    #[serde(rename = "header")]
+    Header,
+    /// Decodes key and value separately and merges them, fields of the key win.
+    #[serde(rename = "key_and_value")]
+    KeyAndValue,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct TopicKeyDecoding {
+    #[serde(default = "TopicDataSerde::default")]
+    pub data_serde: TopicDataSerde,
+    #[serde(default)]
+    pub strict_mapping: bool,
+    #[serde(default)]
+    pub protobuf_mapping: ProtobufMapping,
+    #[serde(default)]
+    pub json_mapping: JsonMapping,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicRoute {
+    /// Header whose value is matched. Checked before the message is decoded.
+    pub header: Option<String>,
+    /// Field of the decoded (and mapped) data whose value is matched.
+    pub field: Option<String>,
+    /// Values of the header or field routed to this listener.
+    pub values: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicDebezium {
+    /// Operations of the change events (`c`, `u`, `d` or `r`) which are processed, e.g. to skip
+    /// snapshot reads. All operations are processed when empty.
+    #[serde(default)]
+    pub ops: Vec<String>,
+    /// If enabled, delete events terminate the subscriptions of the entity like
+    /// `terminates_subscriptions` does.
+    #[serde(default)]
+    pub terminate_on_delete: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TopicDebounce {
+    /// Time without further updates for an entity until the update is published.
+    pub wait_ms: u64,
+    /// Max time an update can be held back by further updates. Unlimited when not set.
+    pub max_wait_ms: Option<u64>,
+    /// Whether the first update of a burst is published right away or held back.
+    #[serde(default)]
+    pub edge: TopicDebounceEdge,
+    /// How updates of a burst are combined into one.
+    #[serde(default)]
+    pub strategy: TopicDebounceStrategy,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum TopicDebounceEdge {
+    /// Publishes the first update of a burst immediately, the rest is combined and published
+    /// when the burst is over.
+    #[serde(rename = "leading")]
+    Leading,
+    /// Combines all updates of a burst and publishes them when the burst is over.
+    #[default]
+    #[serde(rename = "trailing")]
+    Trailing,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum TopicDebounceStrategy {
+    /// Only keeps the latest update.
+    #[default]
+    #[serde(rename = "latest")]
+    Latest,
+    /// Merges the fields of all updates, later values win.
+    #[serde(rename = "merge")]
+    Merge,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub enum TopicPartitionStrategy {
+    /// Hashes the extracted id value of the entity.
+    #[default]
+    #[serde(rename = "id")]
+    Id,
+    /// Uses the partition the message was received on.
+    #[serde(rename = "partition")]
+    Partition,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
+pub struct TopicProcessors(pub usize);
+impl Default for TopicProcessors {
+    fn default() -> Self {
+        TopicProcessors(1)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
+pub struct TopicDispatchConcurrency(pub usize);
+impl Default for TopicDispatchConcurrency {
+    fn default() -> Self {
+        TopicDispatchConcurrency(16)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, From, Into)]
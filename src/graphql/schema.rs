@@ -56,11 +56,13 @@ fn subscriptions(listeners: &Listeners) -> String {
                 \"\"\"
                   {}
                 \"\"\"
-                  {}({}: ID!): {}
+                  {}{}: {}
               "},
                 subscription_description(l),
                 l.operation,
-                l.id_key,
+                l.subscription_argument()
+                    .map(|argument| format!("({argument}: ID!)"))
+                    .unwrap_or_default(),
                 l.entity_name
             )
         })
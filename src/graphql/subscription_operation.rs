@@ -196,7 +196,5 @@ mod tests {
             let operation = SubscriptionOperation::from_query(query, Some(variables.clone()));
             assert_eq!(operation, *result);
         }
-
-        assert!(true);
     }
 }
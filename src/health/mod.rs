@@ -26,7 +26,7 @@ impl Actor for HealthEndpoint {
             .route(&path, routing::get(get_endpoint_handler))
             .with_state(context.clone());
 
-        let _ = tokio::spawn(async move {
+        tokio::spawn(async move {
             tracing::info! { event = "server_starting", hostname, port, path };
             match axum::serve(listener, app.into_make_service()).await {
                 Ok(_) => (),
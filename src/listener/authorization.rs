@@ -0,0 +1,322 @@
+use std::str::FromStr;
+
+use config::{Config, ConfigError};
+use jsonwebtoken::{
+    jwk::{Jwk, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+
+use crate::{configuration, ports::data_serde::ValueMap};
+
+/// Verified claims of the token forwarded by the router.
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug, Clone, Deserialize)]
+struct Configuration {
+    /// Path to a JWKS file with the keys the forwarded tokens are signed with.
+    jwks_file: String,
+    /// Header the router forwards the token in, with or without `Bearer ` prefix.
+    #[serde(default = "default_header")]
+    header: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    /// Algorithm of the keys which don't name one in their `alg`.
+    algorithm: Option<Algorithm>,
+}
+
+fn default_header() -> String {
+    "authorization".to_string()
+}
+
+/// A subscription denied by the authorization rules, as opposed to one which failed otherwise.
+#[derive(Debug)]
+pub(crate) struct Unauthorized(String);
+
+impl std::fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "subscription is not authorized - {}", self.0)
+    }
+}
+impl std::error::Error for Unauthorized {}
+
+/// Verifies the tokens the router forwards with subscription requests against a local JWKS.
+pub(crate) struct Authorizer {
+    keys: Vec<Key>,
+    header: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+/// A key of the JWKS. Its algorithm is pinned, the one named by a token is only checked against
+/// it.
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+}
+
+impl Key {
+    fn new(jwk: &Jwk, algorithm: Option<Algorithm>) -> anyhow::Result<Self> {
+        let kid = jwk.common.key_id.clone();
+        let algorithm = match jwk.common.key_algorithm {
+            Some(key_algorithm) => Algorithm::from_str(&key_algorithm.to_string())
+                .map_err(|_| anyhow::anyhow!("key {kid:?}: {key_algorithm} can't verify tokens"))?,
+            None => algorithm.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "key {kid:?} has no alg, router_endpoint.authorization.algorithm is required"
+                )
+            })?,
+        };
+        Ok(Self { kid, algorithm, decoding_key: DecodingKey::from_jwk(jwk)? })
+    }
+}
+
+impl std::fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kids: Vec<_> = self.keys.iter().map(|key| &key.kid).collect();
+        f.debug_struct("Authorizer").field("kids", &kids).field("header", &self.header).finish()
+    }
+}
+
+impl Authorizer {
+    /// Returns `None` if `router_endpoint.authorization` isn't configured.
+    pub(crate) fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        let configuration = match config.get::<Configuration>("router_endpoint.authorization") {
+            Ok(configuration) => configuration,
+            Err(ConfigError::NotFound(_)) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let jwks: Jwks = serde_json::from_str(&std::fs::read_to_string(&configuration.jwks_file)?)?;
+        let keys = signing_keys(jwks, configuration.algorithm);
+        if keys.is_empty() {
+            anyhow::bail!("{} has no keys to verify tokens with", configuration.jwks_file);
+        }
+
+        Ok(Some(Self {
+            keys,
+            header: configuration.header,
+            issuer: configuration.issuer,
+            audience: configuration.audience,
+        }))
+    }
+
+    pub(crate) fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Checks signature, expiry and, if configured, issuer and audience of the token.
+    pub(crate) fn verify(&self, token: &str) -> anyhow::Result<Claims> {
+        let token = token.strip_prefix("Bearer ").unwrap_or(token);
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = match &header.kid {
+            Some(kid) => self.keys.iter().find(|key| key.kid.as_ref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        };
+        let Some(key) = key else {
+            anyhow::bail!("no key found for kid {:?}", header.kid);
+        };
+
+        // Tokens with another alg than the one of the key are rejected by `decode`.
+        let mut validation = Validation::new(key.algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let token = jsonwebtoken::decode(token, &key.decoding_key, &validation)?;
+        Ok(token.claims)
+    }
+}
+
+/// A JWKS whose keys are parsed one by one, so keys this crate doesn't know don't fail the others.
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<serde_json::Value>,
+}
+
+/// Returns the keys which can verify tokens. Others, e.g. encryption keys published next to the
+/// signing keys, are skipped.
+fn signing_keys(jwks: Jwks, algorithm: Option<Algorithm>) -> Vec<Key> {
+    let key = |jwk: serde_json::Value| -> anyhow::Result<Option<Key>> {
+        let jwk: Jwk = serde_json::from_value(jwk)?;
+        if let Some(PublicKeyUse::Encryption) = jwk.common.public_key_use {
+            return Ok(None);
+        }
+        Key::new(&jwk, algorithm).map(Some)
+    };
+    jwks.keys
+        .into_iter()
+        .filter_map(|jwk| {
+            let kid = jwk.get("kid").cloned();
+            key(jwk).unwrap_or_else(|error| {
+                tracing::warn! { event = "jwk_skipped", ?kid, error = ?error };
+                None
+            })
+        })
+        .collect()
+}
+
+/// Checks that every rule's claim matches the field of the entity. Without claims, e.g. because
+/// the router didn't forward a token, only subscriptions without rules are allowed.
+pub(crate) fn authorize(
+    rules: &[configuration::ListenerAuthorizationRule],
+    claims: Option<&Claims>,
+    entity: &ValueMap,
+) -> anyhow::Result<()> {
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let Some(claims) = claims else {
+        return Err(Unauthorized("no verified claims".to_string()).into());
+    };
+
+    for rule in rules {
+        let Some(expected) = entity.get(&rule.field).map(as_string) else {
+            return Err(Unauthorized(format!("'{}' of the entity is unknown", rule.field)).into());
+        };
+        let matches = match claims.get(&rule.claim) {
+            Some(serde_json::Value::Array(values)) => {
+                values.iter().any(|value| as_string(value) == expected)
+            }
+            Some(value) => as_string(value) == expected,
+            None => false,
+        };
+        if !matches {
+            let reason = format!("claim '{}' doesn't match '{}'", rule.claim, rule.field);
+            return Err(Unauthorized(reason).into());
+        }
+    }
+    Ok(())
+}
+
+fn as_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    fn authorizer(issuer: Option<&str>) -> Authorizer {
+        // base64url of "secret"
+        let jwk = serde_json::from_value(serde_json::json!({
+            "kty": "oct", "kid": "key-1", "alg": "HS256", "k": "c2VjcmV0",
+        }))
+        .unwrap();
+        Authorizer {
+            keys: vec![Key::new(&jwk, None).unwrap()],
+            header: default_header(),
+            issuer: issuer.map(Into::into),
+            audience: None,
+        }
+    }
+
+    fn token(kid: Option<&str>, secret: &str, claims: serde_json::Value) -> String {
+        token_with(Header::default(), kid, secret, claims)
+    }
+
+    fn token_with(
+        header: Header,
+        kid: Option<&str>,
+        secret: &str,
+        claims: serde_json::Value,
+    ) -> String {
+        let header = Header { kid: kid.map(Into::into), ..header };
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let authorizer = authorizer(Some("https://auth.jucr.com"));
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let claims =
+            serde_json::json!({ "sub": "user-1", "iss": "https://auth.jucr.com", "exp": exp });
+
+        let verified = authorizer
+            .verify(&format!("Bearer {}", token(Some("key-1"), "secret", claims.clone())))
+            .unwrap();
+        assert_eq!(verified.get("sub"), Some(&serde_json::json!("user-1")));
+        // The only key is used when the token doesn't name one.
+        assert!(authorizer.verify(&token(None, "secret", claims.clone())).is_ok());
+
+        assert!(authorizer.verify(&token(Some("key-1"), "other", claims.clone())).is_err());
+        assert!(authorizer.verify(&token(Some("key-2"), "secret", claims.clone())).is_err());
+        let expired = serde_json::json!({
+            "sub": "user-1",
+            "iss": "https://auth.jucr.com",
+            "exp": exp - 3600,
+        });
+        assert!(authorizer.verify(&token(Some("key-1"), "secret", expired)).is_err());
+        let foreign = serde_json::json!({ "sub": "user-1", "iss": "https://evil.com", "exp": exp });
+        assert!(authorizer.verify(&token(Some("key-1"), "secret", foreign)).is_err());
+        // The algorithm of the key is pinned, whatever the token names.
+        let header = Header::new(Algorithm::HS512);
+        assert!(authorizer.verify(&token_with(header, Some("key-1"), "secret", claims)).is_err());
+    }
+
+    #[test]
+    fn test_key_algorithm() {
+        let jwk = |alg: Option<&str>| -> Jwk {
+            let mut jwk = serde_json::json!({ "kty": "oct", "k": "c2VjcmV0" });
+            if let Some(alg) = alg {
+                jwk["alg"] = alg.into();
+            }
+            serde_json::from_value(jwk).unwrap()
+        };
+
+        assert_eq!(Key::new(&jwk(Some("HS384")), None).unwrap().algorithm, Algorithm::HS384);
+        assert_eq!(
+            Key::new(&jwk(None), Some(Algorithm::HS512)).unwrap().algorithm,
+            Algorithm::HS512
+        );
+        assert!(Key::new(&jwk(None), None).is_err());
+        assert!(Key::new(&jwk(Some("RSA-OAEP")), None).is_err());
+    }
+
+    #[test]
+    fn test_signing_keys_skip_unusable_keys() {
+        let jwks: Jwks = serde_json::from_value(serde_json::json!({ "keys": [
+            { "kty": "oct", "kid": "signing", "alg": "HS256", "k": "c2VjcmV0" },
+            { "kty": "oct", "kid": "encryption", "use": "enc", "alg": "HS256", "k": "c2VjcmV0" },
+            { "kty": "RSA", "kid": "unknown", "alg": "RSA-OAEP-384", "n": "AQAB", "e": "AQAB" },
+        ]}))
+        .unwrap();
+
+        let keys = signing_keys(jwks, None);
+
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kid.as_deref(), Some("signing"));
+    }
+
+    #[test]
+    fn test_authorize() {
+        let rules = vec![configuration::ListenerAuthorizationRule {
+            claim: "sub".to_string(),
+            field: "ownerId".to_string(),
+        }];
+        let entity = HashMap::from([("ownerId".to_string(), serde_json::json!("user-1"))]);
+        let claims =
+            |sub: serde_json::Value| serde_json::json!({ "sub": sub }).as_object().unwrap().clone();
+
+        assert!(authorize(&rules, Some(&claims("user-1".into())), &entity).is_ok());
+        assert!(authorize(&rules, Some(&claims(serde_json::json!(["user-1"]))), &entity).is_ok());
+        let denied = authorize(&rules, Some(&claims("user-2".into())), &entity).unwrap_err();
+        assert!(denied.is::<Unauthorized>());
+        assert!(authorize(&rules, None, &entity).is_err());
+        assert!(authorize(&rules, Some(&claims("user-1".into())), &HashMap::new()).is_err());
+        assert!(authorize(&[], None, &entity).is_ok());
+    }
+}
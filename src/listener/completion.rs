@@ -0,0 +1,59 @@
+use tokio::sync::oneshot;
+
+/// Reports the outcome of processing a message back to its consumer, which acks or nacks the
+/// message based on it. Updates coalescing several messages, e.g. by debouncing, carry the
+/// completions of all of them. Dropping a completion counts as failure.
+#[derive(Debug, Default)]
+pub(crate) struct Completion(Vec<oneshot::Sender<anyhow::Result<()>>>);
+
+impl Completion {
+    pub(crate) fn new() -> (Self, oneshot::Receiver<anyhow::Result<()>>) {
+        let (sender, receiver) = oneshot::channel();
+        (Self(vec![sender]), receiver)
+    }
+
+    pub(crate) fn merge(&mut self, other: Completion) {
+        self.0.extend(other.0);
+    }
+
+    /// Reports the result to all messages and hands it back to the caller.
+    pub(crate) fn complete(self, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        for sender in self.0 {
+            let _ = sender.send(match &result {
+                Ok(()) => Ok(()),
+                Err(error) => Err(anyhow::anyhow!("{error:#}")),
+            });
+        }
+        result
+    }
+
+    pub(crate) fn succeed(self) {
+        for sender in self.0 {
+            let _ = sender.send(Ok(()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_complete_reports_to_merged() {
+        let (mut completion, first) = Completion::new();
+        let (other, second) = Completion::new();
+        completion.merge(other);
+
+        assert!(completion.complete(Err(anyhow::anyhow!("router unavailable"))).is_err());
+        assert!(first.await.unwrap().is_err());
+        assert!(second.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_fails() {
+        let (completion, result) = Completion::new();
+        drop(completion);
+
+        assert!(result.await.is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use tokio::time::{Duration, Instant};
+
+use crate::{configuration, ports::data_serde::ValueMap};
+
+/// Coalesces bursts of updates per entity. It only keeps the state, timers are scheduled by the
+/// owner based on the returned deadlines.
+pub(crate) struct Debouncer {
+    configuration: configuration::TopicDebounce,
+    entries: HashMap<String, Entry>,
+}
+
+struct Entry {
+    pending: Option<ValueMap>,
+    first_at: Instant,
+    deadline: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Update {
+    /// Data which should be published right away.
+    pub dispatch: Option<ValueMap>,
+    /// When set, the owner needs to call `flush` for this entity at the given time.
+    pub schedule: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Flush {
+    /// The burst is over, publish the data.
+    Dispatch(ValueMap),
+    /// The burst is still ongoing, call `flush` again at the given time.
+    Reschedule(Instant),
+    /// The burst is over and there is nothing left to publish.
+    Nothing,
+}
+
+impl Debouncer {
+    pub(crate) fn new(configuration: configuration::TopicDebounce) -> Self {
+        Self { configuration, entries: HashMap::new() }
+    }
+
+    pub(crate) fn update(&mut self, id_value: &str, data: ValueMap, now: Instant) -> Update {
+        if let Some(entry) = self.entries.get_mut(id_value) {
+            entry.deadline = deadline(&self.configuration, entry.first_at, now);
+            entry.pending = match (entry.pending.take(), &self.configuration.strategy) {
+                (Some(mut pending), configuration::TopicDebounceStrategy::Merge) => {
+                    pending.extend(data);
+                    Some(pending)
+                }
+                _ => Some(data),
+            };
+            return Update { dispatch: None, schedule: None };
+        }
+
+        let deadline = deadline(&self.configuration, now, now);
+        let (pending, dispatch) = match self.configuration.edge {
+            configuration::TopicDebounceEdge::Leading => (None, Some(data)),
+            configuration::TopicDebounceEdge::Trailing => (Some(data), None),
+        };
+        self.entries.insert(id_value.to_string(), Entry { pending, first_at: now, deadline });
+
+        Update { dispatch, schedule: Some(deadline) }
+    }
+
+    pub(crate) fn flush(&mut self, id_value: &str, now: Instant) -> Flush {
+        match self.entries.get(id_value) {
+            Some(entry) if entry.deadline > now => Flush::Reschedule(entry.deadline),
+            Some(_) => match self.entries.remove(id_value).and_then(|entry| entry.pending) {
+                Some(data) => Flush::Dispatch(data),
+                None => Flush::Nothing,
+            },
+            None => Flush::Nothing,
+        }
+    }
+
+    /// Ends the burst of the entity and drops its pending data.
+    pub(crate) fn cancel(&mut self, id_value: &str) {
+        self.entries.remove(id_value);
+    }
+
+    /// Ends all bursts right away and returns the data which is left to publish.
+    pub(crate) fn drain(&mut self) -> Vec<(String, ValueMap)> {
+        self.entries
+            .drain()
+            .filter_map(|(id_value, entry)| Some((id_value, entry.pending?)))
+            .collect()
+    }
+}
+
+fn deadline(
+    configuration: &configuration::TopicDebounce,
+    first_at: Instant,
+    now: Instant,
+) -> Instant {
+    let deadline = now + Duration::from_millis(configuration.wait_ms);
+    match configuration.max_wait_ms {
+        Some(max_wait_ms) => deadline.min(first_at + Duration::from_millis(max_wait_ms)),
+        None => deadline,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debouncer(
+        max_wait_ms: Option<u64>,
+        edge: configuration::TopicDebounceEdge,
+        strategy: configuration::TopicDebounceStrategy,
+    ) -> Debouncer {
+        Debouncer::new(configuration::TopicDebounce { wait_ms: 100, max_wait_ms, edge, strategy })
+    }
+
+    fn data(key: &str, value: i64) -> ValueMap {
+        HashMap::from_iter(vec![(key.to_string(), serde_json::json!(value))])
+    }
+
+    #[test]
+    fn test_trailing_keeps_latest() {
+        let mut debouncer = debouncer(
+            None,
+            configuration::TopicDebounceEdge::Trailing,
+            configuration::TopicDebounceStrategy::Latest,
+        );
+        let start = Instant::now();
+
+        let update = debouncer.update("abc", data("a", 1), start);
+        assert_eq!(
+            update,
+            Update { dispatch: None, schedule: Some(start + Duration::from_millis(100)) }
+        );
+        let update = debouncer.update("abc", data("b", 2), start + Duration::from_millis(50));
+        assert_eq!(update, Update { dispatch: None, schedule: None });
+
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(100)),
+            Flush::Reschedule(start + Duration::from_millis(150))
+        );
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(150)),
+            Flush::Dispatch(data("b", 2))
+        );
+        assert_eq!(debouncer.flush("abc", start + Duration::from_millis(200)), Flush::Nothing);
+    }
+
+    #[test]
+    fn test_trailing_merges() {
+        let mut debouncer = debouncer(
+            None,
+            configuration::TopicDebounceEdge::Trailing,
+            configuration::TopicDebounceStrategy::Merge,
+        );
+        let start = Instant::now();
+
+        debouncer.update("abc", data("a", 1), start);
+        debouncer.update("abc", data("b", 2), start);
+        debouncer.update("abc", data("a", 3), start);
+
+        let mut expected = data("a", 3);
+        expected.extend(data("b", 2));
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(100)),
+            Flush::Dispatch(expected)
+        );
+    }
+
+    #[test]
+    fn test_max_wait() {
+        let mut debouncer = debouncer(
+            Some(150),
+            configuration::TopicDebounceEdge::Trailing,
+            configuration::TopicDebounceStrategy::Latest,
+        );
+        let start = Instant::now();
+
+        debouncer.update("abc", data("a", 1), start);
+        debouncer.update("abc", data("a", 2), start + Duration::from_millis(90));
+        debouncer.update("abc", data("a", 3), start + Duration::from_millis(140));
+
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(100)),
+            Flush::Reschedule(start + Duration::from_millis(150))
+        );
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(150)),
+            Flush::Dispatch(data("a", 3))
+        );
+    }
+
+    #[test]
+    fn test_leading_dispatches_first_update() {
+        let mut debouncer = debouncer(
+            None,
+            configuration::TopicDebounceEdge::Leading,
+            configuration::TopicDebounceStrategy::Latest,
+        );
+        let start = Instant::now();
+
+        let update = debouncer.update("abc", data("a", 1), start);
+        assert_eq!(update.dispatch, Some(data("a", 1)));
+        assert_eq!(debouncer.flush("abc", start + Duration::from_millis(100)), Flush::Nothing);
+
+        let update = debouncer.update("abc", data("a", 2), start + Duration::from_millis(200));
+        assert_eq!(update.dispatch, Some(data("a", 2)));
+        let update = debouncer.update("abc", data("a", 3), start + Duration::from_millis(250));
+        assert_eq!(update.dispatch, None);
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(350)),
+            Flush::Dispatch(data("a", 3))
+        );
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut debouncer = debouncer(
+            None,
+            configuration::TopicDebounceEdge::Leading,
+            configuration::TopicDebounceStrategy::Latest,
+        );
+        let start = Instant::now();
+
+        debouncer.update("abc", data("a", 1), start);
+        debouncer.update("def", data("a", 2), start);
+        debouncer.update("def", data("a", 3), start);
+
+        assert_eq!(debouncer.drain(), vec![("def".to_string(), data("a", 3))]);
+        assert_eq!(debouncer.flush("def", start + Duration::from_millis(100)), Flush::Nothing);
+    }
+
+    #[test]
+    fn test_entities_are_independent() {
+        let mut debouncer = debouncer(
+            None,
+            configuration::TopicDebounceEdge::Trailing,
+            configuration::TopicDebounceStrategy::Latest,
+        );
+        let start = Instant::now();
+
+        debouncer.update("abc", data("a", 1), start);
+        debouncer.update("def", data("a", 2), start);
+
+        assert_eq!(
+            debouncer.flush("abc", start + Duration::from_millis(100)),
+            Flush::Dispatch(data("a", 1))
+        );
+        assert_eq!(
+            debouncer.flush("def", start + Duration::from_millis(100)),
+            Flush::Dispatch(data("a", 2))
+        );
+    }
+}
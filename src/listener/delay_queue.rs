@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ports::kv_store::{KvStore, KvStoreFactory};
+
+use super::{completion::Completion, message_decoder::DecodedMessage};
+
+/// Time a claimed message is hidden from other claims. If it isn't removed by then, e.g. because
+/// its dispatch failed or the instance crashed, it's claimed again.
+const VISIBILITY_TIMEOUT_MS: u64 = 30_000;
+
+/// Messages which are dispatched after a delay. They are kept in the KV store, so they survive
+/// restarts and can be picked up by any instance.
+#[derive(Clone)]
+pub(crate) struct DelayQueue {
+    kv_store: Box<dyn KvStore>,
+    key: String,
+}
+
+impl DelayQueue {
+    pub async fn new(
+        kv_store_factory: Box<dyn KvStoreFactory>,
+        operation: &str,
+    ) -> anyhow::Result<Self> {
+        let kv_store = kv_store_factory.create().await?;
+        Ok(Self { kv_store, key: format!("delayed:{operation}") })
+    }
+
+    pub async fn push(&mut self, message: &DelayedMessage) -> anyhow::Result<()> {
+        self.kv_store
+            .insert_sorted_set_member(
+                self.key.clone(),
+                serde_json::to_vec(message)?,
+                message.due_at,
+            )
+            .await
+    }
+
+    pub async fn len(&mut self) -> anyhow::Result<u64> {
+        self.kv_store.get_sorted_set_len(self.key.clone()).await
+    }
+
+    /// Takes up to `limit` messages which are due at `now`, along with their member in the queue.
+    /// A message stays in the queue until it's removed, but isn't claimed again within the
+    /// visibility timeout, so it's delivered at least once.
+    pub async fn claim_due(
+        &mut self,
+        now: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Vec<u8>, DelayedMessage)>> {
+        let members = self
+            .kv_store
+            .claim_sorted_set_members(self.key.clone(), now, limit, now + VISIBILITY_TIMEOUT_MS)
+            .await?;
+
+        let mut messages = Vec::with_capacity(members.len());
+        for member in members {
+            match serde_json::from_slice(&member) {
+                Ok(message) => messages.push((member, message)),
+                Err(error) => {
+                    tracing::error! {
+                        event = "delayed_message_invalid",
+                        key = self.key,
+                        error = ?error,
+                    };
+                    self.remove(member).await?;
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Removes a claimed message once it was dispatched.
+    pub async fn remove(&mut self, member: Vec<u8>) -> anyhow::Result<()> {
+        self.kv_store.delete_sorted_set_member(self.key.clone(), member).await?;
+        Ok(())
+    }
+}
+
+pub(crate) fn current_timestamp_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DelayedMessage {
+    /// Makes every queued message unique, even if the same content is queued twice.
+    pub id: String,
+    pub topic: String,
+    /// Unix timestamp in milliseconds at which the message should be dispatched.
+    pub due_at: u64,
+    pub message: DecodedMessage,
+}
+
+/// A claimed message which is due. It's removed from the queue once its completion succeeded.
+#[derive(Debug)]
+pub(crate) struct DueMessage {
+    pub message: DelayedMessage,
+    pub completion: Completion,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::adapters::kv_store::InMemoryKvStoreFactory;
+
+    fn delayed_message(id_value: &str, due_at: u64) -> DelayedMessage {
+        DelayedMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            topic: "charging_session_updated".to_string(),
+            due_at,
+            message: DecodedMessage {
+                id_value: id_value.to_string(),
+                data: HashMap::new(),
+                partition: None,
+                op: None,
+                terminates: false,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_claim_due_in_order() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let mut queue = DelayQueue::new(kv_store_factory, "chargingSessionChanged").await.unwrap();
+
+        queue.push(&delayed_message("c", 300)).await.unwrap();
+        queue.push(&delayed_message("a", 100)).await.unwrap();
+        queue.push(&delayed_message("b", 200)).await.unwrap();
+
+        let claimed = queue.claim_due(250, 10).await.unwrap();
+        let id_values: Vec<String> =
+            claimed.iter().map(|(_, m)| m.message.id_value.clone()).collect();
+
+        assert_eq!(id_values, vec!["a", "b"]);
+        for (member, _) in claimed {
+            queue.remove(member).await.unwrap();
+        }
+        assert_eq!(queue.len().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_claim_due_only_once() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let mut first =
+            DelayQueue::new(kv_store_factory.clone(), "chargingSessionChanged").await.unwrap();
+        let mut second = DelayQueue::new(kv_store_factory, "chargingSessionChanged").await.unwrap();
+
+        first.push(&delayed_message("a", 100)).await.unwrap();
+
+        assert_eq!(first.claim_due(100, 10).await.unwrap().len(), 1);
+        assert_eq!(second.claim_due(100, 10).await.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_claim_due_again_unless_removed() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let mut queue = DelayQueue::new(kv_store_factory, "chargingSessionChanged").await.unwrap();
+
+        queue.push(&delayed_message("a", 100)).await.unwrap();
+        queue.claim_due(100, 10).await.unwrap();
+
+        assert_eq!(queue.claim_due(100 + VISIBILITY_TIMEOUT_MS - 1, 10).await.unwrap().len(), 0);
+        let claimed = queue.claim_due(100 + VISIBILITY_TIMEOUT_MS, 10).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        queue.remove(claimed[0].0.clone()).await.unwrap();
+        assert_eq!(queue.len().await.unwrap(), 0);
+    }
+}
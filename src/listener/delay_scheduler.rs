@@ -0,0 +1,120 @@
+use kameo::{
+    actor::ActorRef, mailbox::unbounded::UnboundedMailbox, message::Message, request::MessageSend,
+    Actor,
+};
+
+use crate::{configuration, ports::kv_store::KvStoreFactory};
+
+use super::{
+    completion::Completion,
+    delay_queue::{self, DelayQueue, DueMessage},
+    lease::Lease,
+    topic::TopicListener,
+};
+
+const POLL_INTERVAL_MS: u64 = 250;
+/// Max amount of messages claimed from the queue at once.
+const CLAIM_BATCH_SIZE: usize = 128;
+
+/// Periodically claims due messages from the delay queue of an operation and hands them back to
+/// the topic listener for dispatching. In cluster mode, only the replica holding the lease polls.
+pub(crate) struct DelayScheduler {
+    delay_queue: DelayQueue,
+    lease: Lease,
+    topic_listener: ActorRef<TopicListener>,
+    operation: String,
+}
+
+impl Actor for DelayScheduler {
+    // Only the poll loop sends messages and it waits for each reply, so the mailbox stays small.
+    type Mailbox = UnboundedMailbox<Self>;
+
+    async fn on_start(&mut self, actor_ref: ActorRef<Self>) -> Result<(), kameo::error::BoxError> {
+        tracing::info! {
+            event = "delay_scheduler_started",
+            operation = self.operation,
+            actor = ?actor_ref,
+        };
+
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_millis(POLL_INTERVAL_MS));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            while actor_ref.is_alive() {
+                interval.tick().await;
+                if let Err(error) = actor_ref.ask(PollDelayQueue).send().await {
+                    tracing::error! { event = "delay_queue_poll_failed", ?error };
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl DelayScheduler {
+    pub(crate) async fn spawn(
+        kv_store_factory: Box<dyn KvStoreFactory>,
+        cluster: &configuration::Cluster,
+        topic_listener: ActorRef<TopicListener>,
+        operation: String,
+    ) -> anyhow::Result<ActorRef<Self>> {
+        let delay_queue = DelayQueue::new(kv_store_factory.clone(), &operation).await?;
+        let lease = Lease::new(kv_store_factory, cluster, &format!("delayed:{operation}")).await?;
+        Ok(kameo::spawn(Self { delay_queue, lease, topic_listener, operation }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PollDelayQueue;
+
+impl Message<PollDelayQueue> for DelayScheduler {
+    type Reply = anyhow::Result<()>;
+
+    async fn handle(
+        &mut self,
+        _message: PollDelayQueue,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        if !self.lease.acquire().await? {
+            return Ok(());
+        }
+
+        loop {
+            let messages = self
+                .delay_queue
+                .claim_due(delay_queue::current_timestamp_ms(), CLAIM_BATCH_SIZE)
+                .await?;
+            let count = messages.len();
+            if count > 0 {
+                tracing::debug! {
+                    event = "delayed_messages_claimed",
+                    operation = self.operation,
+                    count,
+                };
+            }
+
+            for (member, message) in messages {
+                let (completion, result) = Completion::new();
+                self.topic_listener.tell(DueMessage { message, completion }).send().await?;
+                // Failed messages stay in the queue and are claimed again after the visibility
+                // timeout.
+                let mut delay_queue = self.delay_queue.clone();
+                tokio::spawn(async move {
+                    let result = match result.await {
+                        Ok(Ok(())) => delay_queue.remove(member).await,
+                        Ok(Err(error)) => Err(error),
+                        Err(_) => Err(anyhow::anyhow!("delayed message was dropped unprocessed")),
+                    };
+                    if let Err(error) = result {
+                        tracing::warn! { event = "delayed_message_failed", ?error };
+                    }
+                });
+            }
+
+            // When the batch was full, there might be more due messages waiting.
+            if count < CLAIM_BATCH_SIZE {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use futures_util::StreamExt;
+
+use crate::{
+    configuration,
+    ports::{
+        data_serde::ValueMap,
+        router_client::{self, RouterClient},
+    },
+};
+
+use super::subscription_store::{SubscriptionRecord, SubscriptionStore};
+
+/// Sends updates of a single entity to all of its subscribers.
+#[derive(Clone)]
+pub(crate) struct Dispatcher {
+    router_client: Box<dyn RouterClient>,
+    subscription_store: SubscriptionStore,
+    listener_configuration: configuration::Listener,
+    topic_configuration: configuration::Topic,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(
+        router_client: Box<dyn RouterClient>,
+        subscription_store: SubscriptionStore,
+        listener_configuration: configuration::Listener,
+        topic_configuration: configuration::Topic,
+    ) -> Self {
+        Self { router_client, subscription_store, listener_configuration, topic_configuration }
+    }
+
+    /// Dispatches the update to all subscriptions with at most `dispatch_concurrency` requests in
+    /// flight. Subscriptions are grouped by the host of their callback URL and interleaved, so a
+    /// single slow router instance can't take up all slots. With `terminates`, or when the topic
+    /// terminates subscriptions, the subscriptions are completed after the update.
+    pub(crate) async fn dispatch_all(
+        &self,
+        subscriptions: Vec<SubscriptionRecord>,
+        id_value: &str,
+        data: &ValueMap,
+        terminates: bool,
+    ) {
+        let concurrency = self.topic_configuration.dispatch_concurrency.0.max(1);
+        futures_util::stream::iter(interleave_by_host(subscriptions))
+            .map(|subscription| async move {
+                if let Err(error) =
+                    self.dispatch(subscription, id_value, data.clone(), terminates).await
+                {
+                    tracing::error! {
+                        event = "dispatch_failed",
+                        error = ?error,
+                        id_value,
+                        topic = self.topic_configuration.name,
+                    };
+                }
+            })
+            .buffer_unordered(concurrency)
+            .collect::<()>()
+            .await;
+    }
+
+    async fn dispatch(
+        &self,
+        subscription: SubscriptionRecord,
+        id_value: &str,
+        data: ValueMap,
+        terminates: bool,
+    ) -> anyhow::Result<()> {
+        let next_request = router_client::Request::subscription(
+            &subscription.callback_url,
+            &subscription.id,
+            &subscription.verifier,
+        )
+        .next(
+            &self.listener_configuration.operation,
+            &self.listener_configuration.entity_name,
+            data,
+        )
+        .to_owned();
+
+        let response = self.router_client.send(&next_request).await;
+        tracing::debug! {
+            event = "dispatch_request_sent",
+            request = ?&next_request,
+            response = ?&response,
+        };
+
+        // If the request to router fails, we assume the subscription is gone and remove it.
+        // This behavior is stated in the specification.
+        if let Err(error) = response {
+            tracing::warn! {
+                event = "dispatch_request_failed",
+                error = ?error,
+                subscription_id = subscription.id,
+                id_value,
+                topic = self.topic_configuration.name,
+            };
+            self.subscription_store.clone().delete(subscription.key(), subscription.id()).await?;
+            return Ok(());
+        }
+
+        tracing::debug! {
+            event = "subscription_update_dispatched",
+            subscription_id = subscription.id,
+            id_value,
+            topic = self.topic_configuration.name,
+        };
+
+        // When the topic or the message terminates subscriptions, we remove the subscription.
+        // Subscriptions to several entities outlive the termination of a single one.
+        let terminates = self.topic_configuration.terminates_subscriptions || terminates;
+        if terminates
+            && self.listener_configuration.subscribe_by == configuration::ListenerSubscribeBy::Id
+        {
+            let complete_request = router_client::Request::subscription(
+                &subscription.callback_url,
+                &subscription.id,
+                &subscription.verifier,
+            )
+            .complete(None)
+            .to_owned();
+            // Fire and forget as we delete the key afterwards anyways.
+            let _ = self.router_client.send(&complete_request).await;
+            self.subscription_store.clone().delete(subscription.key(), subscription.id()).await?;
+
+            tracing::debug! {
+                event = "subscription_terminated",
+                subscription_id = subscription.id,
+                id_value,
+                topic = self.topic_configuration.name,
+            };
+        }
+
+        Ok(())
+    }
+}
+
+fn callback_host(subscription: &SubscriptionRecord) -> String {
+    reqwest::Url::parse(&subscription.callback_url)
+        .ok()
+        .and_then(|url| Some(format!("{}:{}", url.host_str()?, url.port_or_known_default()?)))
+        .unwrap_or_default()
+}
+
+/// Groups the subscriptions by callback host and takes one of each group in turn.
+fn interleave_by_host(subscriptions: Vec<SubscriptionRecord>) -> Vec<SubscriptionRecord> {
+    let mut batches: BTreeMap<String, VecDeque<SubscriptionRecord>> = BTreeMap::new();
+    let count = subscriptions.len();
+    for subscription in subscriptions {
+        batches.entry(callback_host(&subscription)).or_default().push_back(subscription);
+    }
+
+    let mut result = Vec::with_capacity(count);
+    while result.len() < count {
+        for batch in batches.values_mut() {
+            if let Some(subscription) = batch.pop_front() {
+                result.push(subscription);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use async_trait::async_trait;
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{
+        adapters::kv_store::InMemoryKvStoreFactory,
+        ports::router_client::{Request, Response},
+    };
+
+    /// Takes a second per request and tracks the max amount of requests in flight.
+    #[derive(Clone, Default)]
+    struct SlowRouterClient {
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl RouterClient for SlowRouterClient {
+        async fn send(&self, _request: &Request) -> anyhow::Result<Response> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(Response {
+                status_code: StatusCode::NO_CONTENT,
+                subscription_protocol: None,
+                errors: None,
+            })
+        }
+
+        fn clone_box(&self) -> Box<dyn RouterClient> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn subscription(id: &str, callback_url: &str) -> SubscriptionRecord {
+        SubscriptionRecord {
+            id: id.to_string(),
+            created_at: 0,
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms: 0,
+            callback_url: callback_url.to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_interleave_by_host() {
+        let subscriptions = vec![
+            subscription("1", "http://10.0.0.1:8001/callback/1"),
+            subscription("2", "http://10.0.0.1:8001/callback/2"),
+            subscription("3", "http://10.0.0.1:8001/callback/3"),
+            subscription("4", "http://10.0.0.2:8001/callback/4"),
+            subscription("5", "http://10.0.0.2:8001/callback/5"),
+        ];
+        let ids: Vec<String> =
+            interleave_by_host(subscriptions).into_iter().map(|s| s.id).collect();
+
+        assert_eq!(ids, vec!["1", "4", "2", "5", "3"]);
+    }
+
+    #[test]
+    fn test_interleave_by_host_with_invalid_url() {
+        let subscriptions =
+            vec![subscription("1", "not a url"), subscription("2", "http://10.0.0.1/callback")];
+
+        assert_eq!(interleave_by_host(subscriptions).len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dispatch_all_is_bounded() {
+        let router_client = SlowRouterClient::default();
+        let subscription_store =
+            SubscriptionStore::new(Box::new(InMemoryKvStoreFactory::new())).await.unwrap();
+        let listener_configuration = serde_json::from_value(serde_json::json!({
+            "operation": "chargingSessionChanged",
+            "entity_name": "ChargingSession",
+            "id_key": "id",
+            "ttl_ms": 60000,
+            "topics": [],
+        }))
+        .unwrap();
+        let topic_configuration = serde_json::from_value(serde_json::json!({
+            "name": "charging_session_updated",
+            "dispatch_concurrency": 5,
+        }))
+        .unwrap();
+        let dispatcher = Dispatcher::new(
+            Box::new(router_client.clone()),
+            subscription_store,
+            listener_configuration,
+            topic_configuration,
+        );
+
+        let subscriptions = (0..10)
+            .map(|id| subscription(&id.to_string(), &format!("http://10.0.0.{}/callback", id % 3)))
+            .collect();
+        let started_at = tokio::time::Instant::now();
+        dispatcher.dispatch_all(subscriptions, "abc", &HashMap::new(), false).await;
+
+        assert_eq!(router_client.max_in_flight.load(Ordering::SeqCst), 5);
+        assert_eq!(started_at.elapsed(), std::time::Duration::from_secs(2));
+    }
+}
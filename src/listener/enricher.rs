@@ -0,0 +1,244 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use tokio::time::{Duration, Instant};
+
+use crate::{configuration, graphql, ports::data_serde::ValueMap};
+
+/// Fetches the entity via `_entities` from a GraphQL endpoint and merges it into the payload of an
+/// update, so the router doesn't need to resolve the fields itself. Results are cached per entity
+/// and event, so an update dispatched again doesn't query the endpoint again.
+#[derive(Clone)]
+pub(crate) struct Enricher {
+    client: reqwest::Client,
+    configuration: configuration::ListenerEnrichment,
+    entity_name: String,
+    id_key: String,
+    query: String,
+    cache: Arc<Mutex<Cache>>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<(String, u64), (Instant, ValueMap)>,
+    /// Keys in insertion order, the oldest entry is evicted first when the cache is full.
+    order: VecDeque<(String, u64)>,
+}
+
+#[derive(Deserialize)]
+struct EntitiesResponse {
+    data: Option<EntitiesData>,
+    #[serde(default)]
+    errors: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct EntitiesData {
+    #[serde(rename = "_entities")]
+    entities: Vec<Option<ValueMap>>,
+}
+
+impl Enricher {
+    /// Returns `None` if the listener has no enrichment configured.
+    pub(crate) fn new(listener: &configuration::Listener) -> anyhow::Result<Option<Self>> {
+        let Some(configuration) = listener.enrichment.clone() else {
+            return Ok(None);
+        };
+        let query = format!(
+            "query PathfinderEntities($representations: [_Any!]!) {{ \
+             _entities(representations: $representations) {{ ... on {} {{ {} }} }} }}",
+            listener.entity_name, configuration.selection
+        );
+        // The timeout covers the whole request, including reading the response.
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(configuration.timeout_ms.0))
+            .build()?;
+        Ok(Some(Self {
+            client,
+            configuration,
+            entity_name: listener.entity_name.clone(),
+            id_key: listener.id_key.clone(),
+            query,
+            cache: Default::default(),
+        }))
+    }
+
+    /// Merges the fetched entity into the data, fields of the event win. When the entity can't be
+    /// fetched, the data is returned as is and the router resolves the fields as usual.
+    pub(crate) async fn enrich(&self, id_value: &str, data: ValueMap) -> ValueMap {
+        let key = (id_value.to_string(), event_hash(&data));
+        if let Some(entity) = self.cached(&key) {
+            return merge(entity, data);
+        }
+
+        match self.fetch(id_value).await {
+            Ok(Some(entity)) => {
+                self.cache(key, entity.clone());
+                merge(entity, data)
+            }
+            Ok(None) => {
+                tracing::debug! {
+                    event = "enrichment_entity_not_found",
+                    entity_name = self.entity_name,
+                    id_value,
+                };
+                data
+            }
+            Err(error) => {
+                tracing::warn! {
+                    event = "enrichment_failed",
+                    entity_name = self.entity_name,
+                    id_value,
+                    error = ?error,
+                };
+                data
+            }
+        }
+    }
+
+    async fn fetch(&self, id_value: &str) -> anyhow::Result<Option<ValueMap>> {
+        let body = serde_json::json!({
+            "query": self.query,
+            "variables": {
+                "representations": [{
+                    graphql::TYPENAME_KEY: self.entity_name,
+                    self.id_key.as_str(): id_value,
+                }],
+            },
+        });
+        let mut request = self.client.post(&self.configuration.url).json(&body);
+        for (name, value) in &self.configuration.headers {
+            request = request.header(name, value);
+        }
+
+        let response = request.send().await?.error_for_status()?;
+        let response: EntitiesResponse = response.json().await?;
+
+        let entity = response.data.and_then(|data| data.entities.into_iter().next().flatten());
+        if entity.is_none() && !response.errors.is_empty() {
+            anyhow::bail!("endpoint returned errors: {:?}", response.errors);
+        }
+        Ok(entity)
+    }
+
+    fn cached(&self, key: &(String, u64)) -> Option<ValueMap> {
+        let cache = self.cache.lock().unwrap();
+        let (cached_at, entity) = cache.entries.get(key)?;
+        let ttl = Duration::from_millis(self.configuration.cache_ttl_ms.0);
+        (cached_at.elapsed() < ttl).then(|| entity.clone())
+    }
+
+    fn cache(&self, key: (String, u64), entity: ValueMap) {
+        let mut cache = self.cache.lock().unwrap();
+        while cache.entries.len() >= self.configuration.cache_size.0.max(1) {
+            let Some(oldest) = cache.order.pop_front() else {
+                break;
+            };
+            cache.entries.remove(&oldest);
+        }
+        if cache.entries.insert(key.clone(), (Instant::now(), entity)).is_none() {
+            cache.order.push_back(key);
+        }
+    }
+}
+
+fn merge(mut entity: ValueMap, data: ValueMap) -> ValueMap {
+    entity.extend(data);
+    entity
+}
+
+/// Hashes the data independent of the iteration order of the map.
+fn event_hash(data: &ValueMap) -> u64 {
+    let sorted: BTreeMap<&String, &serde_json::Value> = data.iter().collect();
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&sorted).unwrap_or_default().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{extract::State, routing, Json, Router};
+
+    use super::*;
+
+    /// Serves `_entities` like a subgraph would and counts the requests.
+    async fn serve_entities(requests: Arc<AtomicUsize>) -> String {
+        async fn entities_handler(
+            State(requests): State<Arc<AtomicUsize>>,
+            Json(body): Json<serde_json::Value>,
+        ) -> Json<serde_json::Value> {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let representation = &body["variables"]["representations"][0];
+            let entity = match representation["id"].as_str() {
+                Some("abc") => serde_json::json!({
+                    "__typename": "ChargingSession",
+                    "status": "charging",
+                    "energyKwh": 12.5,
+                }),
+                _ => serde_json::Value::Null,
+            };
+            Json(serde_json::json!({ "data": { "_entities": [entity] } }))
+        }
+
+        let app =
+            Router::new().route("/graphql", routing::post(entities_handler)).with_state(requests);
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service()).await.unwrap();
+        });
+        format!("http://{address}/graphql")
+    }
+
+    fn enricher(url: &str) -> Enricher {
+        let listener: configuration::Listener = serde_json::from_value(serde_json::json!({
+            "operation": "chargingSessionChanged",
+            "entity_name": "ChargingSession",
+            "id_key": "id",
+            "ttl_ms": 60000,
+            "topics": [],
+            "enrichment": { "url": url, "selection": "status energyKwh" },
+        }))
+        .unwrap();
+        Enricher::new(&listener).unwrap().unwrap()
+    }
+
+    fn data(id: &str, status: &str) -> ValueMap {
+        HashMap::from([
+            ("id".to_string(), serde_json::json!(id)),
+            ("status".to_string(), serde_json::json!(status)),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_enrich_merges_entity() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let enricher = enricher(&serve_entities(requests.clone()).await);
+
+        let enriched = enricher.enrich("abc", data("abc", "stopped")).await;
+        assert_eq!(enriched.get("energyKwh"), Some(&serde_json::json!(12.5)));
+        // The event is more recent than the entity of the endpoint.
+        assert_eq!(enriched.get("status"), Some(&serde_json::json!("stopped")));
+
+        enricher.enrich("abc", data("abc", "stopped")).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        enricher.enrich("abc", data("abc", "charging")).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_enrich_keeps_data_without_entity() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let available = enricher(&serve_entities(requests).await);
+        let unavailable = enricher("http://127.0.0.1:1/graphql");
+
+        assert_eq!(available.enrich("def", data("def", "started")).await, data("def", "started"));
+        assert_eq!(unavailable.enrich("abc", data("abc", "started")).await, data("abc", "started"));
+    }
+}
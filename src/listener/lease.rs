@@ -0,0 +1,114 @@
+use crate::{
+    configuration,
+    ports::kv_store::{KvStore, KvStoreFactory},
+};
+
+/// Decides which replica runs a periodic task. In cluster mode, the lease is kept in the KV store
+/// and held by one replica at a time; it has to be renewed by calling `acquire` regularly. Outside
+/// of cluster mode, every replica holds every lease.
+#[derive(Clone)]
+pub(crate) struct Lease {
+    kv_store: Option<Box<dyn KvStore>>,
+    key: String,
+    owner: String,
+    ttl_ms: u64,
+    held: bool,
+}
+
+impl Lease {
+    pub async fn new(
+        kv_store_factory: Box<dyn KvStoreFactory>,
+        cluster: &configuration::Cluster,
+        name: &str,
+    ) -> anyhow::Result<Self> {
+        let kv_store = match cluster.enabled {
+            true => Some(kv_store_factory.create().await?),
+            false => None,
+        };
+        let owner = cluster.instance_id.clone().unwrap_or_default();
+        Ok(Self {
+            kv_store,
+            key: format!("lease:{name}"),
+            owner,
+            ttl_ms: cluster.lease_ttl_ms.0,
+            held: false,
+        })
+    }
+
+    /// Acquires or renews the lease. Returns whether this replica holds it.
+    pub async fn acquire(&mut self) -> anyhow::Result<bool> {
+        let Some(kv_store) = &mut self.kv_store else {
+            return Ok(true);
+        };
+
+        let held =
+            kv_store.acquire_lease(self.key.clone(), self.owner.clone(), self.ttl_ms).await?;
+        if held != self.held {
+            tracing::info! {
+                event = if held { "lease_acquired" } else { "lease_lost" },
+                key = self.key,
+                owner = self.owner,
+            };
+            self.held = held;
+        }
+        Ok(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::kv_store::InMemoryKvStoreFactory;
+
+    fn cluster(instance_id: &str) -> configuration::Cluster {
+        configuration::Cluster {
+            enabled: true,
+            instance_id: Some(instance_id.to_string()),
+            lease_ttl_ms: 1000.into(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_is_held_by_one_replica() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let mut first = Lease::new(kv_store_factory.clone(), &cluster("a"), "sweep").await.unwrap();
+        let mut second = Lease::new(kv_store_factory, &cluster("b"), "sweep").await.unwrap();
+
+        assert!(first.acquire().await.unwrap());
+        assert!(!second.acquire().await.unwrap());
+
+        // Renewing keeps the lease with the first replica.
+        tokio::time::advance(std::time::Duration::from_millis(800)).await;
+        assert!(first.acquire().await.unwrap());
+        tokio::time::advance(std::time::Duration::from_millis(800)).await;
+        assert!(!second.acquire().await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_is_taken_over_after_expiry() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let mut first = Lease::new(kv_store_factory.clone(), &cluster("a"), "sweep").await.unwrap();
+        let mut second = Lease::new(kv_store_factory, &cluster("b"), "sweep").await.unwrap();
+
+        assert!(first.acquire().await.unwrap());
+        tokio::time::advance(std::time::Duration::from_millis(1000)).await;
+
+        assert!(second.acquire().await.unwrap());
+        assert!(!first.acquire().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_lease_is_always_held_without_cluster() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let mut first =
+            Lease::new(kv_store_factory.clone(), &configuration::Cluster::default(), "sweep")
+                .await
+                .unwrap();
+        let mut second = Lease::new(kv_store_factory, &configuration::Cluster::default(), "sweep")
+            .await
+            .unwrap();
+
+        assert!(first.acquire().await.unwrap());
+        assert!(second.acquire().await.unwrap());
+    }
+}
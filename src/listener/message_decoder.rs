@@ -0,0 +1,789 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adapters::data_serde,
+    configuration,
+    ports::{
+        data_serde::{DataSerde, ValueMap},
+        message_consumer::RawMessage,
+    },
+};
+
+/// Header marking a Kafka message as CloudEvent in binary mode.
+const CLOUD_EVENT_SPECVERSION_HEADER: &str = "ce_specversion";
+
+/// Decodes the payload of raw messages of a single topic. Listeners sharing a topic with the same
+/// decoding settings share a decoder, so every message is decoded once, see `decoding_key`.
+pub(crate) struct MessageDecoder {
+    data_serde: Box<dyn DataSerde>,
+    /// Only set when key and value are decoded separately.
+    key_data_serde: Option<Box<dyn DataSerde>>,
+    /// Only set for CloudEvents, whose values are extracted along with their type.
+    cloud_events: Option<data_serde::CloudEventsDataSerde>,
+    topic_configuration: configuration::Topic,
+}
+
+impl MessageDecoder {
+    pub(crate) fn new(topic: configuration::Topic) -> anyhow::Result<Self> {
+        if matches!(topic.data_source, configuration::TopicDataSource::Header)
+            && !matches!(topic.data_serde, configuration::TopicDataSerde::Json)
+        {
+            anyhow::bail!("topic {}: data_source header requires data_serde json", topic.name);
+        }
+        if matches!(topic.data_serde, configuration::TopicDataSerde::CloudEvents)
+            && matches!(topic.data_source, configuration::TopicDataSource::Key)
+        {
+            anyhow::bail!("topic {}: data_source key doesn't support cloudevents", topic.name);
+        }
+        if topic.debezium.is_some() {
+            if !matches!(topic.data_serde, configuration::TopicDataSerde::Json) {
+                anyhow::bail!("topic {}: debezium requires data_serde json", topic.name);
+            }
+            if !matches!(
+                topic.data_source,
+                configuration::TopicDataSource::Value | configuration::TopicDataSource::KeyAndValue
+            ) {
+                anyhow::bail!(
+                    "topic {}: debezium requires data_source value or key_and_value",
+                    topic.name
+                );
+            }
+        }
+
+        let data_serde = create_data_serde(
+            &topic.data_serde,
+            &topic.json_mapping,
+            topic.strict_mapping,
+            &topic.protobuf_mapping,
+        )?;
+        let key_data_serde = match topic.data_source {
+            configuration::TopicDataSource::KeyAndValue => {
+                let key_decoding = &topic.key_decoding;
+                Some(create_data_serde(
+                    &key_decoding.data_serde,
+                    &key_decoding.json_mapping,
+                    key_decoding.strict_mapping,
+                    &key_decoding.protobuf_mapping,
+                )?)
+            }
+            _ => None,
+        };
+
+        let cloud_events = match topic.data_serde {
+            configuration::TopicDataSerde::CloudEvents => {
+                Some(data_serde::CloudEventsDataSerde::new(
+                    topic.json_mapping.clone(),
+                    topic.strict_mapping,
+                )?)
+            }
+            _ => None,
+        };
+
+        Ok(Self { data_serde, key_data_serde, cloud_events, topic_configuration: topic })
+    }
+
+    /// Identifies the settings of a topic which affect decoding. Topics with the same key decode
+    /// messages to the same data.
+    pub(crate) fn decoding_key(topic: &configuration::Topic) -> anyhow::Result<String> {
+        // Going through `Value` sorts the keys of the mappings.
+        let key = serde_json::to_value((
+            &topic.data_serde,
+            &topic.data_source,
+            &topic.key_decoding,
+            &topic.header_mapping,
+            &topic.timestamp_key,
+            topic.strict_mapping,
+            &topic.protobuf_mapping,
+            &topic.json_mapping,
+            topic.debezium.is_some(),
+        ))?;
+        Ok(key.to_string())
+    }
+
+    /// Returns `None` for messages without anything to process, like Debezium tombstones.
+    pub(crate) async fn decode(
+        &self,
+        mut message: RawMessage,
+    ) -> anyhow::Result<Option<DecodedData>> {
+        let mut op = None;
+        if self.topic_configuration.debezium.is_some() {
+            let Some((row, event_op)) = debezium_change_event(&message.value)? else {
+                tracing::trace! {
+                    event = "message_skipped",
+                    reason = "tombstone",
+                    topic = message.topic,
+                    partition = message.partition,
+                    offset = message.offset,
+                };
+                return Ok(None);
+            };
+            message.value = row;
+            op = Some(event_op);
+        }
+        let mut event_type = None;
+        let mut data = match self.topic_configuration.data_source {
+            configuration::TopicDataSource::Key => {
+                self.data_serde.extract_values(message.key.unwrap_or_default()).await?
+            }
+            configuration::TopicDataSource::Value => {
+                let (data, value_event_type) =
+                    self.extract_value(&message.headers, message.value).await?;
+                event_type = value_event_type;
+                data
+            }
+            configuration::TopicDataSource::Header => {
+                self.data_serde.extract_values(headers_to_json(&message.headers)).await?
+            }
+            configuration::TopicDataSource::KeyAndValue => {
+                let (mut data, value_event_type) =
+                    self.extract_value(&message.headers, message.value).await?;
+                event_type = value_event_type;
+                if let Some(key_data_serde) = &self.key_data_serde {
+                    data.extend(
+                        key_data_serde.extract_values(message.key.unwrap_or_default()).await?,
+                    );
+                }
+                data
+            }
+        };
+        for (field, header) in &self.topic_configuration.header_mapping {
+            if let Some(value) = message.headers.get(header) {
+                data.insert(field.clone(), String::from_utf8_lossy(value).into_owned().into());
+            }
+        }
+        if let (Some(timestamp_key), Some(timestamp)) =
+            (&self.topic_configuration.timestamp_key, message.timestamp)
+        {
+            data.insert(timestamp_key.clone(), timestamp.into());
+        }
+
+        Ok(Some(DecodedData { data, event_type, op }))
+    }
+
+    /// Extracts the values of the message value, along with the type if it's a CloudEvent.
+    async fn extract_value(
+        &self,
+        headers: &HashMap<String, Vec<u8>>,
+        value: Vec<u8>,
+    ) -> anyhow::Result<(ValueMap, Option<String>)> {
+        let Some(cloud_events) = &self.cloud_events else {
+            return Ok((self.data_serde.extract_values(value).await?, None));
+        };
+        let event = if headers.contains_key(CLOUD_EVENT_SPECVERSION_HEADER) {
+            binary_cloud_event(headers, &value)?
+        } else if value.is_empty() {
+            return Ok((HashMap::new(), None));
+        } else {
+            serde_json::from_slice(&value)?
+        };
+        let event = cloud_events.extract_event(event)?;
+        Ok((event.values, event.event_type))
+    }
+}
+
+/// The payload of a message, before it's selected by the listeners of the topic.
+#[derive(Debug, Clone)]
+pub(crate) struct DecodedData {
+    pub data: ValueMap,
+    /// Type of the CloudEvent, only set when data_serde is cloudevents.
+    pub event_type: Option<String>,
+    /// Operation of the Debezium change event, only set when debezium is configured.
+    pub op: Option<String>,
+}
+
+/// Decides whether a message of a single topic is meant for a listener and extracts the id value
+/// of the entity, so messages can be routed to the processor responsible for that entity.
+pub(crate) struct MessageSelector {
+    id_key: String,
+    topic_configuration: configuration::Topic,
+}
+
+impl MessageSelector {
+    pub(crate) fn new(id_key: String, topic: configuration::Topic) -> anyhow::Result<Self> {
+        if !topic.event_types.is_empty()
+            && !matches!(topic.data_serde, configuration::TopicDataSerde::CloudEvents)
+        {
+            anyhow::bail!("topic {}: event_types requires data_serde cloudevents", topic.name);
+        }
+        if let Some(route) = &topic.route {
+            if route.header.is_some() == route.field.is_some() {
+                anyhow::bail!("topic {}: route needs either a header or a field", topic.name);
+            }
+        }
+
+        Ok(Self { id_key, topic_configuration: topic })
+    }
+
+    /// Checks the route by header, which doesn't need the message to be decoded.
+    pub(crate) fn accepts(&self, message: &RawMessage) -> bool {
+        let route = self.topic_configuration.route.as_ref();
+        let Some((route, header)) = route.and_then(|route| Some((route, route.header.as_ref()?)))
+        else {
+            return true;
+        };
+        let value = message.headers.get(header).map(|value| String::from_utf8_lossy(value));
+        if !route.values.iter().any(|route_value| Some(route_value.as_str()) == value.as_deref()) {
+            self.trace_not_routed(message);
+            return false;
+        }
+        true
+    }
+
+    /// Returns `None` when the message isn't meant for the listener or doesn't contain a usable
+    /// id value.
+    pub(crate) fn select(
+        &self,
+        message: &RawMessage,
+        decoded: &DecodedData,
+    ) -> Option<DecodedMessage> {
+        let (partition, offset) = (message.partition, message.offset);
+        let event_types = &self.topic_configuration.event_types;
+        if !event_types.is_empty()
+            && !decoded
+                .event_type
+                .as_ref()
+                .is_some_and(|event_type| event_types.contains(event_type))
+        {
+            tracing::trace! {
+                event = "message_skipped",
+                reason = "event_type",
+                event_type = decoded.event_type,
+                topic = self.topic_configuration.name,
+                partition = partition,
+                offset = offset,
+            };
+            return None;
+        }
+
+        if let (Some(debezium), Some(op)) = (&self.topic_configuration.debezium, &decoded.op) {
+            if !debezium.ops.is_empty() && !debezium.ops.contains(op) {
+                tracing::trace! {
+                    event = "message_skipped",
+                    reason = "op",
+                    op = op,
+                    topic = self.topic_configuration.name,
+                    partition = partition,
+                    offset = offset,
+                };
+                return None;
+            }
+        }
+
+        let data = &decoded.data;
+        let route = self.topic_configuration.route.as_ref();
+        if let Some((route, field)) = route.and_then(|route| Some((route, route.field.as_ref()?))) {
+            let value = data.get(field).map(|value| match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            });
+            if !route.values.iter().any(|route_value| Some(route_value) == value.as_ref()) {
+                self.trace_not_routed(message);
+                return None;
+            }
+        }
+
+        let id_value = data.get(&self.id_key);
+        let id_value = if let Some(serde_json::Value::String(id_value)) = id_value {
+            tracing::debug! {
+                event = "id_value_extracted",
+                id_key = self.id_key,
+                id_value = id_value,
+                topic = self.topic_configuration.name,
+                partition = partition,
+                offset = offset,
+            };
+            id_value.to_owned()
+        } else {
+            tracing::warn! {
+                event = "id_value_not_found",
+                message = "value not found or not a string",
+                id_key = self.id_key,
+                id_value = ?id_value,
+                topic = self.topic_configuration.name,
+                partition = partition,
+                offset = offset,
+            };
+            return None;
+        };
+
+        let terminates = self.topic_configuration.debezium.as_ref().is_some_and(|debezium| {
+            debezium.terminate_on_delete && decoded.op.as_deref() == Some("d")
+        });
+
+        Some(DecodedMessage {
+            id_value,
+            data: data.clone(),
+            partition,
+            op: decoded.op.clone(),
+            terminates,
+        })
+    }
+
+    fn trace_not_routed(&self, message: &RawMessage) {
+        tracing::trace! {
+            event = "message_skipped",
+            reason = "route",
+            topic = self.topic_configuration.name,
+            partition = message.partition,
+            offset = message.offset,
+        };
+    }
+}
+
+fn create_data_serde(
+    data_serde: &configuration::TopicDataSerde,
+    json_mapping: &configuration::JsonMapping,
+    strict_mapping: bool,
+    protobuf_mapping: &configuration::ProtobufMapping,
+) -> anyhow::Result<Box<dyn DataSerde>> {
+    Ok(match data_serde {
+        configuration::TopicDataSerde::Json => {
+            Box::new(data_serde::JsonDataSerde::new(json_mapping.clone(), strict_mapping)?)
+        }
+        configuration::TopicDataSerde::CloudEvents => {
+            Box::new(data_serde::CloudEventsDataSerde::new(json_mapping.clone(), strict_mapping)?)
+        }
+        configuration::TopicDataSerde::Protobuf => {
+            Box::new(data_serde::ProtobufDataSerde::new(protobuf_mapping.clone(), false)?)
+        }
+        configuration::TopicDataSerde::ProtobufWire => {
+            Box::new(data_serde::ProtobufDataSerde::new(protobuf_mapping.clone(), true)?)
+        }
+    })
+}
+
+/// Builds the structured event from a CloudEvent in binary mode, with the attributes in `ce_`
+/// headers and the data as value.
+fn binary_cloud_event(
+    headers: &HashMap<String, Vec<u8>>,
+    value: &[u8],
+) -> anyhow::Result<serde_json::Value> {
+    let mut event: serde_json::Map<String, serde_json::Value> = headers
+        .iter()
+        .filter_map(|(key, value)| {
+            let attribute = key.strip_prefix(data_serde::cloudevents::ATTRIBUTE_PREFIX)?;
+            Some((attribute.to_string(), String::from_utf8_lossy(value).into_owned().into()))
+        })
+        .collect();
+    if !value.is_empty() {
+        event.insert("data".to_string(), serde_json::from_slice(value)?);
+    }
+    Ok(event.into())
+}
+
+/// Unwraps the changed row from a Debezium change event: `after` for creates, updates and
+/// snapshot reads, `before` for deletes. Events wrapped with their schema (`schemas.enable`) are
+/// supported as well. Returns the row with the operation, or `None` for tombstones.
+fn debezium_change_event(value: &[u8]) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let mut envelope: serde_json::Value = serde_json::from_slice(value)?;
+    if envelope.get("schema").is_some() {
+        if let Some(payload) = envelope.get_mut("payload") {
+            envelope = payload.take();
+        }
+    }
+    let serde_json::Value::Object(mut envelope) = envelope else {
+        anyhow::bail!("debezium change event is not an object");
+    };
+
+    let Some(serde_json::Value::String(op)) = envelope.remove("op") else {
+        anyhow::bail!("debezium change event has no op");
+    };
+    let row = match op.as_str() {
+        "d" => envelope.remove("before"),
+        _ => envelope.remove("after"),
+    };
+    let row = match row {
+        Some(row @ serde_json::Value::Object(_)) => serde_json::to_vec(&row)?,
+        Some(serde_json::Value::Null) | None => Vec::new(),
+        Some(_) => anyhow::bail!("debezium change event has no row object"),
+    };
+    Ok(Some((row, op)))
+}
+
+/// Serializes the headers as a json object of strings, so they can be run through the json serde
+/// and its mapping like any other payload.
+fn headers_to_json(headers: &HashMap<String, Vec<u8>>) -> Vec<u8> {
+    let headers: serde_json::Map<String, serde_json::Value> = headers
+        .iter()
+        .map(|(key, value)| (key.clone(), String::from_utf8_lossy(value).into_owned().into()))
+        .collect();
+    serde_json::Value::Object(headers).to_string().into_bytes()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DecodedMessage {
+    pub id_value: String,
+    pub data: ValueMap,
+    pub partition: Option<i32>,
+    /// Operation of the Debezium change event. It's not part of the data, which only contains
+    /// the row.
+    #[serde(default)]
+    pub op: Option<String>,
+    /// Whether this message terminates the subscriptions of the entity, independent of the
+    /// `terminates_subscriptions` setting of the topic.
+    #[serde(default)]
+    pub terminates: bool,
+}
+
+impl DecodedMessage {
+    /// Picks one of `processors` for this message. The same entity (or partition) always ends up
+    /// on the same processor, which keeps the ordering of its messages intact.
+    pub(crate) fn processor_index(
+        &self,
+        strategy: &configuration::TopicPartitionStrategy,
+        processors: usize,
+    ) -> usize {
+        if processors <= 1 {
+            return 0;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        match (strategy, self.partition) {
+            (configuration::TopicPartitionStrategy::Partition, Some(partition)) => {
+                partition.hash(&mut hasher)
+            }
+            _ => self.id_value.hash(&mut hasher),
+        }
+        (hasher.finish() % processors as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(data_source: &str, timestamp_key: Option<&str>) -> configuration::Topic {
+        serde_json::from_value(serde_json::json!({
+            "name": "charging-sessions",
+            "data_source": data_source,
+            "timestamp_key": timestamp_key,
+        }))
+        .unwrap()
+    }
+
+    /// Decodes like the `MessageRouter` does for a single listener.
+    struct TestDecoder(MessageDecoder, MessageSelector);
+
+    impl TestDecoder {
+        fn new(id_key: String, topic: configuration::Topic) -> anyhow::Result<Self> {
+            Ok(Self(MessageDecoder::new(topic.clone())?, MessageSelector::new(id_key, topic)?))
+        }
+
+        async fn decode(&self, message: RawMessage) -> anyhow::Result<Option<DecodedMessage>> {
+            if !self.1.accepts(&message) {
+                return Ok(None);
+            }
+            let Some(decoded) = self.0.decode(message.clone()).await? else {
+                return Ok(None);
+            };
+            Ok(self.1.select(&message, &decoded))
+        }
+    }
+
+    fn decoded_message(id_value: &str, partition: Option<i32>) -> DecodedMessage {
+        DecodedMessage {
+            id_value: id_value.to_string(),
+            data: HashMap::new(),
+            partition,
+            op: None,
+            terminates: false,
+        }
+    }
+
+    #[test]
+    fn test_processor_index_is_stable_per_entity() {
+        let strategy = configuration::TopicPartitionStrategy::Id;
+        let first = decoded_message("abc", Some(0)).processor_index(&strategy, 8);
+        let second = decoded_message("abc", Some(5)).processor_index(&strategy, 8);
+
+        assert_eq!(first, second);
+        assert!(first < 8);
+    }
+
+    #[test]
+    fn test_processor_index_spreads_entities() {
+        let strategy = configuration::TopicPartitionStrategy::Id;
+        let indexes: std::collections::HashSet<usize> = (0..64)
+            .map(|id| decoded_message(&format!("id-{id}"), None).processor_index(&strategy, 4))
+            .collect();
+
+        assert_eq!(indexes.len(), 4);
+    }
+
+    #[test]
+    fn test_processor_index_by_partition() {
+        let strategy = configuration::TopicPartitionStrategy::Partition;
+        let first = decoded_message("abc", Some(3)).processor_index(&strategy, 4);
+        let second = decoded_message("def", Some(3)).processor_index(&strategy, 4);
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_processor_index_single_processor() {
+        let strategy = configuration::TopicPartitionStrategy::Id;
+        assert_eq!(decoded_message("abc", None).processor_index(&strategy, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_decode_from_headers() {
+        let decoder = TestDecoder::new("id".to_string(), topic("header", None)).unwrap();
+        let message = RawMessage {
+            value: b"ignored".to_vec(),
+            topic: "charging-sessions".to_string(),
+            headers: HashMap::from([
+                ("id".to_string(), b"abc".to_vec()),
+                ("status".to_string(), b"charging".to_vec()),
+            ]),
+            partition: Some(2),
+            offset: Some(42),
+            ..Default::default()
+        };
+
+        let decoded = decoder.decode(message).await.unwrap().unwrap();
+
+        assert_eq!(decoded.id_value, "abc");
+        assert_eq!(decoded.data.get("status"), Some(&serde_json::json!("charging")));
+        assert_eq!(decoded.partition, Some(2));
+    }
+
+    #[test]
+    fn test_header_source_requires_json() {
+        let mut topic = topic("header", None);
+        topic.data_serde = configuration::TopicDataSerde::Protobuf;
+
+        assert!(MessageDecoder::new(topic).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decode_adds_timestamp() {
+        let decoder =
+            TestDecoder::new("id".to_string(), topic("value", Some("updatedAt"))).unwrap();
+        let message = RawMessage {
+            value: br#"{"id": "abc"}"#.to_vec(),
+            topic: "charging-sessions".to_string(),
+            timestamp: Some(1_700_000_000_000),
+            ..Default::default()
+        };
+
+        let decoded = decoder.decode(message).await.unwrap().unwrap();
+
+        assert_eq!(decoded.data.get("updatedAt"), Some(&serde_json::json!(1_700_000_000_000_i64)));
+    }
+
+    #[tokio::test]
+    async fn test_decode_maps_headers() {
+        let mut topic = topic("value", None);
+        topic.header_mapping.insert("id".to_string(), "entity-id".to_string());
+        let decoder = TestDecoder::new("id".to_string(), topic).unwrap();
+        let message = RawMessage {
+            value: br#"{"id": "ignored", "status": "charging"}"#.to_vec(),
+            topic: "charging-sessions".to_string(),
+            headers: HashMap::from([("entity-id".to_string(), b"abc".to_vec())]),
+            ..Default::default()
+        };
+
+        let decoded = decoder.decode(message).await.unwrap().unwrap();
+
+        assert_eq!(decoded.id_value, "abc");
+        assert_eq!(decoded.data.get("status"), Some(&serde_json::json!("charging")));
+    }
+
+    #[tokio::test]
+    async fn test_decode_merges_key_and_value() {
+        let topic: configuration::Topic = serde_json::from_value(serde_json::json!({
+            "name": "charging-sessions",
+            "data_source": "key_and_value",
+            "key_decoding": {
+                "json_mapping": { "id": "sessionId" },
+            },
+        }))
+        .unwrap();
+        let decoder = TestDecoder::new("id".to_string(), topic).unwrap();
+        let message = RawMessage {
+            key: Some(br#"{"sessionId": "abc"}"#.to_vec()),
+            value: br#"{"id": "ignored", "status": "charging"}"#.to_vec(),
+            topic: "charging-sessions".to_string(),
+            ..Default::default()
+        };
+
+        let decoded = decoder.decode(message).await.unwrap().unwrap();
+
+        assert_eq!(decoded.id_value, "abc");
+        assert_eq!(decoded.data.get("status"), Some(&serde_json::json!("charging")));
+    }
+
+    #[tokio::test]
+    async fn test_decode_debezium() {
+        let topic: configuration::Topic = serde_json::from_value(serde_json::json!({
+            "name": "pg.public.charging_sessions",
+            "data_source": "value",
+            "json_mapping": { "id": "session_id" },
+            "debezium": { "ops": ["c", "u", "d"], "terminate_on_delete": true },
+        }))
+        .unwrap();
+        let decoder = TestDecoder::new("id".to_string(), topic).unwrap();
+        let message = |value: serde_json::Value| RawMessage {
+            value: serde_json::to_vec(&value).unwrap(),
+            topic: "pg.public.charging_sessions".to_string(),
+            ..Default::default()
+        };
+
+        let updated = decoder
+            .decode(message(serde_json::json!({
+                "before": null,
+                "after": { "session_id": "abc", "status": "charging" },
+                "source": { "table": "charging_sessions" },
+                "op": "c",
+            })))
+            .await
+            .unwrap()
+            .unwrap();
+        let deleted = decoder
+            .decode(message(serde_json::json!({
+                "schema": {},
+                "payload": {
+                    "before": { "session_id": "abc", "status": "charging" },
+                    "after": null,
+                    "op": "d",
+                },
+            })))
+            .await
+            .unwrap()
+            .unwrap();
+        let read = decoder
+            .decode(message(serde_json::json!({
+                "before": null,
+                "after": { "session_id": "abc", "status": "charging" },
+                "op": "r",
+            })))
+            .await
+            .unwrap();
+        let tombstone = RawMessage {
+            key: Some(br#"{"session_id": "abc"}"#.to_vec()),
+            topic: "pg.public.charging_sessions".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(updated.id_value, "abc");
+        assert_eq!(
+            updated.data,
+            HashMap::from([
+                ("id".to_string(), serde_json::json!("abc")),
+                ("status".to_string(), serde_json::json!("charging")),
+            ])
+        );
+        assert_eq!(updated.op.as_deref(), Some("c"));
+        assert!(!updated.terminates);
+        assert_eq!(deleted.id_value, "abc");
+        assert!(deleted.terminates);
+        assert!(read.is_none());
+        assert!(decoder.decode(tombstone).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_decode_cloud_events_by_type() {
+        let topic: configuration::Topic = serde_json::from_value(serde_json::json!({
+            "name": "charging-sessions",
+            "data_source": "value",
+            "data_serde": "cloudevents",
+            "event_types": ["com.jucr.charging_session.started"],
+            "json_mapping": { "id": "sessionId", "source": "ce_source" },
+        }))
+        .unwrap();
+        let decoder = TestDecoder::new("id".to_string(), topic).unwrap();
+        let binary = |event_type: &str| RawMessage {
+            value: br#"{"sessionId": "abc"}"#.to_vec(),
+            topic: "charging-sessions".to_string(),
+            headers: HashMap::from([
+                ("ce_specversion".to_string(), b"1.0".to_vec()),
+                ("ce_type".to_string(), event_type.as_bytes().to_vec()),
+                ("ce_source".to_string(), b"/charging-sessions".to_vec()),
+            ]),
+            ..Default::default()
+        };
+        let structured = RawMessage {
+            value: serde_json::to_vec(&serde_json::json!({
+                "specversion": "1.0",
+                "type": "com.jucr.charging_session.started",
+                "source": "/charging-sessions",
+                "data": { "sessionId": "def" },
+            }))
+            .unwrap(),
+            topic: "charging-sessions".to_string(),
+            ..Default::default()
+        };
+
+        let started = decoder.decode(binary("com.jucr.charging_session.started")).await.unwrap();
+        let stopped = decoder.decode(binary("com.jucr.charging_session.stopped")).await.unwrap();
+        let structured = decoder.decode(structured).await.unwrap().unwrap();
+
+        let started = started.unwrap();
+        assert_eq!(started.id_value, "abc");
+        assert_eq!(started.data.get("source"), Some(&serde_json::json!("/charging-sessions")));
+        assert!(!started.data.contains_key("ce_type"));
+        assert!(stopped.is_none());
+        assert_eq!(structured.id_value, "def");
+    }
+
+    #[tokio::test]
+    async fn test_decode_by_route() {
+        let topic = |route: serde_json::Value| -> configuration::Topic {
+            serde_json::from_value(serde_json::json!({
+                "name": "charging-sessions",
+                "data_source": "value",
+                "route": route,
+            }))
+            .unwrap()
+        };
+        let message = |kind: &str| RawMessage {
+            value: serde_json::to_vec(&serde_json::json!({ "id": "abc", "kind": kind })).unwrap(),
+            topic: "charging-sessions".to_string(),
+            headers: HashMap::from([("kind".to_string(), kind.as_bytes().to_vec())]),
+            ..Default::default()
+        };
+        let by_header = TestDecoder::new(
+            "id".to_string(),
+            topic(serde_json::json!({ "header": "kind", "values": ["started"] })),
+        )
+        .unwrap();
+        let by_field = TestDecoder::new(
+            "id".to_string(),
+            topic(serde_json::json!({ "field": "kind", "values": ["started", "stopped"] })),
+        )
+        .unwrap();
+
+        assert!(by_header.decode(message("started")).await.unwrap().is_some());
+        assert!(by_header.decode(message("stopped")).await.unwrap().is_none());
+        assert!(by_field.decode(message("stopped")).await.unwrap().is_some());
+        assert!(by_field.decode(message("updated")).await.unwrap().is_none());
+        assert!(TestDecoder::new(
+            "id".to_string(),
+            topic(serde_json::json!({ "header": "kind", "field": "kind", "values": [] })),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_decoding_key() {
+        let mut routed = topic("value", None);
+        routed.route =
+            serde_json::from_value(serde_json::json!({ "header": "kind", "values": ["started"] }))
+                .unwrap();
+        let mut mapped = topic("value", None);
+        mapped.json_mapping.0.insert("id".to_string(), "sessionId".to_string());
+
+        let key = MessageDecoder::decoding_key(&topic("value", None)).unwrap();
+        assert_eq!(MessageDecoder::decoding_key(&routed).unwrap(), key);
+        assert_ne!(MessageDecoder::decoding_key(&mapped).unwrap(), key);
+        assert_ne!(MessageDecoder::decoding_key(&topic("key", None)).unwrap(), key);
+    }
+}
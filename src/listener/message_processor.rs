@@ -1,29 +1,53 @@
+use std::{collections::HashMap, sync::Arc};
+
 use kameo::{
     actor::ActorRef, mailbox::bounded::BoundedMailbox, message::Message, request::MessageSend,
     Actor,
 };
+use tokio::{sync::Semaphore, task::JoinHandle};
 
 use crate::{
-    adapters::data_serde,
     configuration,
-    ports::{
-        data_serde::{DataSerde, ValueMap},
-        kv_store::KvStoreFactory,
-        message_consumer,
-        router_client::{self, RouterClient},
-    },
+    ports::{data_serde::ValueMap, kv_store::KvStoreFactory, router_client::RouterClient},
 };
 
-use super::subscription_store::{SubscriptionKey, SubscriptionRecord, SubscriptionStore};
+use super::{
+    completion::Completion,
+    debouncer::{self, Debouncer},
+    delay_queue::{self, DelayQueue, DelayedMessage, DueMessage},
+    dispatcher::Dispatcher,
+    enricher::Enricher,
+    message_decoder::DecodedMessage,
+    state_store::{self, StateStore},
+    subscription_store::{SubscriptionKey, SubscriptionRecord, SubscriptionStore, ALL_ENTITIES},
+    topic::TopicMessage,
+    Drain,
+};
 
 const MAILBOX_CAP: usize = 128;
+/// Max amount of updates which are dispatched (or waiting for a previous update of the same
+/// entity) at the same time. When reached, the mailbox fills up and the topic listener is slowed
+/// down.
+const MAX_PENDING_DISPATCHES: usize = 256;
+/// Max amount of delayed updates per operation kept in the KV store. When reached, further updates
+/// are rejected instead of piling up.
+const MAX_QUEUED_DISPATCHES: u64 = 100_000;
 
 pub(crate) struct MessageProcessor {
-    router_client: Box<dyn RouterClient>,
-    data_serde: Box<dyn DataSerde>,
+    index: usize,
+    dispatcher: Dispatcher,
+    enricher: Option<Enricher>,
     subscription_store: SubscriptionStore,
+    state_store: Option<StateStore>,
     listener_configuration: configuration::Listener,
     topic_configuration: configuration::Topic,
+    dispatch_permits: Arc<Semaphore>,
+    debouncer: Option<Debouncer>,
+    /// Completions of the messages coalesced into the pending update of an entity.
+    debounced: HashMap<String, Completion>,
+    delay_queue: Option<DelayQueue>,
+    /// The latest dispatch per id value which might still be in flight.
+    dispatches: HashMap<String, JoinHandle<()>>,
 }
 
 impl Actor for MessageProcessor {
@@ -37,6 +61,7 @@ impl Actor for MessageProcessor {
         tracing::info! {
             event = "message_processor_started",
             topic = self.topic_configuration.name,
+            index = self.index,
         };
         Ok(())
     }
@@ -49,6 +74,7 @@ impl Actor for MessageProcessor {
         tracing::error! {
             event = "processing_failed",
             topic = self.topic_configuration.name,
+            index = self.index,
             error = error.to_string(),
         };
         Ok(None)
@@ -57,83 +83,90 @@ impl Actor for MessageProcessor {
 
 impl MessageProcessor {
     pub(crate) async fn spawn(
+        index: usize,
         router_client: Box<dyn RouterClient>,
         kv_store_factory: Box<dyn KvStoreFactory>,
         configuration: configuration::Listener,
         topic: configuration::Topic,
     ) -> anyhow::Result<ActorRef<Self>> {
+        if topic.delay_ms.is_some() && topic.debounce.is_some() {
+            anyhow::bail!("delay_ms and debounce can't be combined on topic '{}'", topic.name);
+        }
         let subscription_store = SubscriptionStore::new(kv_store_factory.clone()).await?;
-        let data_serde: Box<dyn DataSerde> = match &topic.data_serde {
-            configuration::TopicDataSerde::Json => Box::new(data_serde::JsonDataSerde::new(
-                topic.json_mapping.clone(),
-                topic.strict_mapping,
-            )?),
-            configuration::TopicDataSerde::Protobuf => {
-                Box::new(data_serde::ProtobufDataSerde::new(topic.protobuf_mapping.clone(), false)?)
-            }
-            configuration::TopicDataSerde::ProtobufWire => {
-                Box::new(data_serde::ProtobufDataSerde::new(topic.protobuf_mapping.clone(), true)?)
-            }
+        let state_store = match configuration.state_ttl_ms {
+            Some(ttl_ms) => Some(StateStore::new(kv_store_factory.clone(), ttl_ms).await?),
+            None => None,
         };
 
-        let message_processor = Self {
+        let debounce = topic.debounce.clone();
+        let delay_queue = match topic.delay_ms {
+            Some(_) => Some(DelayQueue::new(kv_store_factory, &configuration.operation).await?),
+            None => None,
+        };
+        let dispatcher = Dispatcher::new(
             router_client,
+            subscription_store.clone(),
+            configuration.clone(),
+            topic.clone(),
+        );
+
+        let message_processor = Self {
+            index,
+            dispatcher,
+            enricher: Enricher::new(&configuration)?,
             subscription_store,
+            state_store,
             listener_configuration: configuration,
             topic_configuration: topic,
-            data_serde,
+            dispatch_permits: Arc::new(Semaphore::new(MAX_PENDING_DISPATCHES)),
+            debouncer: debounce.map(Debouncer::new),
+            debounced: HashMap::new(),
+            delay_queue,
+            dispatches: HashMap::new(),
         };
 
         let actor_ref = kameo::spawn(message_processor);
         Ok(actor_ref)
     }
-}
-
-impl Message<message_consumer::RawMessage> for MessageProcessor {
-    type Reply = anyhow::Result<()>;
 
-    async fn handle(
+    /// Looks up the subscriptions for the entity and dispatches the update to them. With
+    /// `terminates`, the subscriptions are completed afterwards. The completion is reported once
+    /// the update was dispatched.
+    async fn process(
         &mut self,
-        message: message_consumer::RawMessage,
-        ctx: kameo::message::Context<'_, Self, Self::Reply>,
-    ) -> Self::Reply {
-        let data = match self.topic_configuration.data_source {
-            configuration::TopicDataSource::Key => message.key.unwrap_or_default(),
-            configuration::TopicDataSource::Value => message.value,
-        };
-        let data = self.data_serde.extract_values(data).await?;
+        id_value: String,
+        data: ValueMap,
+        terminates: bool,
+        completion: Completion,
+    ) -> anyhow::Result<()> {
+        match self.prepare_dispatch(id_value, data, terminates).await {
+            Ok(Some(message)) => self.dispatch(message, completion).await,
+            result => completion.complete(result.map(|_| ())),
+        }
+    }
 
-        let id_value = data.get(&self.listener_configuration.id_key);
-        let id_value = if let Some(serde_json::Value::String(id_value)) = id_value {
-            tracing::debug! {
-                event = "id_value_extracted",
-                id_key = self.listener_configuration.id_key,
-                id_value = id_value,
-                topic = self.topic_configuration.name,
-            };
-            id_value.to_owned()
-        } else {
-            tracing::warn! {
-                event = "id_value_not_found",
-                message = "value not found or not a string",
-                id_key = self.listener_configuration.id_key,
-                id_value = ?id_value,
-                topic = self.topic_configuration.name,
-            };
-            return Ok(());
+    /// Updates the state of the entity and looks up its subscriptions. Returns `None` if there
+    /// is nothing to dispatch.
+    async fn prepare_dispatch(
+        &mut self,
+        id_value: String,
+        data: ValueMap,
+        terminates: bool,
+    ) -> anyhow::Result<Option<DispatchSubscriptions>> {
+        // Resolved before the state update, which might leave only the changed fields.
+        let subscription_key = self.subscription_key(&id_value, &data);
+        let Some(data) = self.update_state(&id_value, data, terminates).await? else {
+            return Ok(None);
         };
 
-        // Get all subscriptions for the id_value.
+        // Get all subscriptions for the id_value (or the value subscriptions are made by).
         // When there are no subscriptions, return early.
-        let subscriptions = self
-            .subscription_store
-            .get_all(SubscriptionKey {
-                operation: self.listener_configuration.operation.clone(),
-                operation_id_value: id_value.clone(),
-            })
-            .await?;
+        let Some(subscription_key) = subscription_key else {
+            return Ok(None);
+        };
+        let subscriptions = self.subscription_store.get_all(subscription_key).await?;
         if subscriptions.is_empty() {
-            return Ok(());
+            return Ok(None);
         }
 
         tracing::debug! {
@@ -142,108 +175,548 @@ impl Message<message_consumer::RawMessage> for MessageProcessor {
             topic = self.topic_configuration.name,
         };
 
-        let actor_ref = ctx.actor_ref();
-        for subscription in subscriptions {
-            let dispatch = DispatchSubscription {
-                subscription,
-                id_value: id_value.clone(),
-                data: data.clone(),
-            };
-            if let Some(delay_ms) = self.topic_configuration.delay_ms {
-                let actor_ref = actor_ref.clone();
-                tokio::spawn(async move {
-                    tokio::time::sleep(tokio::time::Duration::from_millis(delay_ms)).await;
-                    let _ = actor_ref.tell(dispatch).send().await;
-                });
-            } else {
-                actor_ref.tell(dispatch).send().await?;
+        Ok(Some(DispatchSubscriptions { subscriptions, id_value, data, terminates }))
+    }
+
+    /// Returns the key the subscriptions to the entity are stored under, depending on what they
+    /// are made by. Returns `None` if the entity lacks the field subscriptions are made by.
+    fn subscription_key(&self, id_value: &str, data: &ValueMap) -> Option<SubscriptionKey> {
+        let operation_id_value = match &self.listener_configuration.subscribe_by {
+            configuration::ListenerSubscribeBy::Id => id_value.to_string(),
+            configuration::ListenerSubscribeBy::Field(field) => match data.get(field) {
+                Some(serde_json::Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+                None => {
+                    tracing::debug! {
+                        event = "subscription_value_not_found",
+                        id_value,
+                        topic = self.topic_configuration.name,
+                    };
+                    return None;
+                }
+            },
+            configuration::ListenerSubscribeBy::All => ALL_ENTITIES.to_string(),
+        };
+        Some(SubscriptionKey {
+            operation: self.listener_configuration.operation.clone(),
+            operation_id_value,
+        })
+    }
+
+    /// Updates the state of the entity, if it's kept. With `publish_deltas`, returns only the
+    /// changed fields and `None` if nothing changed.
+    async fn update_state(
+        &mut self,
+        id_value: &str,
+        data: ValueMap,
+        terminates: bool,
+    ) -> anyhow::Result<Option<ValueMap>> {
+        let Some(state_store) = &mut self.state_store else {
+            return Ok(Some(data));
+        };
+        let key = SubscriptionKey {
+            operation: self.listener_configuration.operation.clone(),
+            operation_id_value: id_value.to_string(),
+        };
+        // The state is kept for entities without subscribers as well, so their first subscriber
+        // gets it right away. Terminated entities have no state anymore.
+        if terminates {
+            state_store.delete(&key).await?;
+            return Ok(Some(data));
+        }
+        let previous = state_store.update(&key, &data).await?.unwrap_or_default();
+        if !self.listener_configuration.publish_deltas {
+            return Ok(Some(data));
+        }
+
+        // Subscribers by field need it to tell which of their entities the update belongs to.
+        let subscription_field = match &self.listener_configuration.subscribe_by {
+            configuration::ListenerSubscribeBy::Field(field) => {
+                data.get(field).map(|value| (field.clone(), value.clone()))
             }
+            _ => None,
+        };
+        // The timestamp changes with every event, but isn't a change of the entity.
+        let injected = self.topic_configuration.timestamp_key.as_slice();
+        let mut data = state_store::changed_fields(&previous, data, injected);
+        if data.is_empty() {
+            tracing::debug! {
+                event = "update_unchanged",
+                id_value,
+                topic = self.topic_configuration.name,
+            };
+            return Ok(None);
+        }
+        data.insert(self.listener_configuration.id_key.clone(), id_value.into());
+        data.extend(subscription_field);
+        Ok(Some(data))
+    }
+
+    /// Puts the update into the delay queue. The subscriptions are looked up again once it is
+    /// due, as they might have changed in the meantime. Returns the message if it has to be
+    /// processed right away instead.
+    async fn delay(
+        &mut self,
+        message: DecodedMessage,
+        delay_ms: u64,
+    ) -> anyhow::Result<Option<DecodedMessage>> {
+        let subscriptions = match self.subscription_key(&message.id_value, &message.data) {
+            Some(key) => self.subscription_store.get_all(key).await?,
+            None => Vec::new(),
+        };
+        // Updates of entities without subscribers aren't delayed, only their state is kept.
+        if subscriptions.is_empty() {
+            let DecodedMessage { id_value, data, terminates, .. } = message;
+            self.update_state(&id_value, data, terminates).await?;
+            return Ok(None);
         }
 
+        let Some(delay_queue) = &mut self.delay_queue else {
+            return Ok(Some(message));
+        };
+
+        // The update is rejected, so it's redelivered by consumers which support it. Dispatching
+        // it right away would break the delay, which might be relied on by the subscribers.
+        if delay_queue.len().await? >= MAX_QUEUED_DISPATCHES {
+            tracing::error! {
+                event = "delay_queue_full",
+                topic = self.topic_configuration.name,
+                id_value = message.id_value,
+            };
+            anyhow::bail!("delay queue of '{}' is full", self.listener_configuration.operation);
+        }
+
+        delay_queue
+            .push(&DelayedMessage {
+                id: uuid::Uuid::new_v4().to_string(),
+                topic: self.topic_configuration.name.clone(),
+                due_at: delay_queue::current_timestamp_ms() + delay_ms,
+                message,
+            })
+            .await?;
+        Ok(None)
+    }
+
+    /// Dispatches the update in the background. If there is still an update in flight for the
+    /// same entity, the new one waits for it to keep the ordering for the subscribers.
+    async fn dispatch(
+        &mut self,
+        message: DispatchSubscriptions,
+        completion: Completion,
+    ) -> anyhow::Result<()> {
+        let permit = match self.dispatch_permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(error) => return completion.complete(Err(error.into())),
+        };
+        self.dispatches.retain(|_, dispatch| !dispatch.is_finished());
+
+        let previous = self.dispatches.remove(&message.id_value);
+        let dispatcher = self.dispatcher.clone();
+        let enricher = self.enricher.clone();
+        let id_value = message.id_value.clone();
+        let dispatch = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let data = match enricher {
+                Some(enricher) => enricher.enrich(&message.id_value, message.data).await,
+                None => message.data,
+            };
+            dispatcher
+                .dispatch_all(message.subscriptions, &message.id_value, &data, message.terminates)
+                .await;
+            completion.succeed();
+            drop(permit);
+        });
+        self.dispatches.insert(id_value, dispatch);
+
         Ok(())
     }
 }
 
-impl Message<DispatchSubscription> for MessageProcessor {
+impl Message<TopicMessage> for MessageProcessor {
     type Reply = anyhow::Result<()>;
 
     async fn handle(
         &mut self,
-        message: DispatchSubscription,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        message: TopicMessage,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        let next_request = router_client::Request::subscription(
-            &message.subscription.callback_url,
-            &message.subscription.id,
-            &message.subscription.verifier,
-        )
-        .next(
-            &self.listener_configuration.operation,
-            &self.listener_configuration.entity_name,
-            message.data,
-        )
-        .to_owned();
-
-        let response = self.router_client.send(&next_request).await;
-        tracing::debug! {
-            event = "dispatch_request_sent",
-            request = ?&next_request,
-            response = ?&response,
-        };
-
-        // If the request to router fails, we assume the subscription is gone and remove it.
-        // This behavior is stated in the specification.
-        if let Err(error) = response {
-            tracing::warn! {
-                event = "dispatch_request_failed",
-                error = ?error,
-                subscription_id = message.subscription.id,
-                id_value = message.id_value,
-                topic = self.topic_configuration.name,
+        let TopicMessage { message, mut completion, .. } = message;
+        if let Some(delay_ms) = self.topic_configuration.delay_ms {
+            return match self.delay(message, delay_ms).await {
+                Ok(Some(message)) => {
+                    let DecodedMessage { id_value, data, terminates, .. } = message;
+                    self.process(id_value, data, terminates, completion).await
+                }
+                result => completion.complete(result.map(|_| ())),
             };
-            self.subscription_store
-                .delete(message.subscription.key(), message.subscription.id())
-                .await?;
-            return Ok(());
         }
 
-        tracing::debug! {
-            event = "subscription_update_dispatched",
-            subscription_id = message.subscription.id,
-            id_value = message.id_value,
-            topic = self.topic_configuration.name,
+        let DecodedMessage { id_value, data, terminates, .. } = message;
+        let data = if let Some(debouncer) = &mut self.debouncer {
+            // Nothing is published for the entity after it was terminated, so updates of the
+            // current burst are dropped and the final one goes out right away.
+            if terminates {
+                debouncer.cancel(&id_value);
+                if let Some(debounced) = self.debounced.remove(&id_value) {
+                    completion.merge(debounced);
+                }
+                return self.process(id_value, data, true, completion).await;
+            }
+
+            let update = debouncer.update(&id_value, data, tokio::time::Instant::now());
+            if let Some(deadline) = update.schedule {
+                schedule_flush(ctx.actor_ref(), id_value.clone(), deadline);
+            }
+            match update.dispatch {
+                Some(data) => data,
+                None => {
+                    self.debounced.entry(id_value).or_default().merge(completion);
+                    return Ok(());
+                }
+            }
+        } else {
+            data
         };
 
-        // When the topic is configured to terminate subscriptions, we remove the subscription.
-        if self.topic_configuration.terminates_subscriptions {
-            let complete_request = router_client::Request::subscription(
-                &message.subscription.callback_url,
-                &message.subscription.id,
-                &message.subscription.verifier,
-            )
-            .complete(None)
-            .to_owned();
-            // Fire and forget as we delete the key afterwards anyways.
-            let _ = self.router_client.send(&complete_request).await;
-            self.subscription_store
-                .delete(message.subscription.key(), message.subscription.id())
-                .await?;
+        self.process(id_value, data, terminates, completion).await
+    }
+}
 
-            tracing::debug! {
-                event = "subscription_terminated",
-                subscription_id = message.subscription.id,
-                id_value = message.id_value,
-                topic = self.topic_configuration.name,
-            };
+impl Message<FlushDebounced> for MessageProcessor {
+    type Reply = anyhow::Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: FlushDebounced,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let Some(debouncer) = &mut self.debouncer else {
+            return Ok(());
+        };
+
+        match debouncer.flush(&message.id_value, tokio::time::Instant::now()) {
+            debouncer::Flush::Dispatch(data) => {
+                let completion = self.debounced.remove(&message.id_value).unwrap_or_default();
+                self.process(message.id_value, data, false, completion).await
+            }
+            debouncer::Flush::Reschedule(deadline) => {
+                schedule_flush(ctx.actor_ref(), message.id_value, deadline);
+                Ok(())
+            }
+            debouncer::Flush::Nothing => {
+                if let Some(completion) = self.debounced.remove(&message.id_value) {
+                    completion.succeed();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Message<Drain> for MessageProcessor {
+    type Reply = anyhow::Result<()>;
+
+    async fn handle(
+        &mut self,
+        _message: Drain,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let pending = self.debouncer.as_mut().map(Debouncer::drain).unwrap_or_default();
+        for (id_value, data) in pending {
+            let completion = self.debounced.remove(&id_value).unwrap_or_default();
+            self.process(id_value, data, false, completion).await?;
+        }
+        for (_, completion) in self.debounced.drain() {
+            completion.succeed();
         }
 
+        for (_, dispatch) in self.dispatches.drain() {
+            let _ = dispatch.await;
+        }
         Ok(())
     }
 }
 
+impl Message<DueMessage> for MessageProcessor {
+    type Reply = anyhow::Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: DueMessage,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let DecodedMessage { id_value, data, terminates, .. } = message.message.message;
+        self.process(id_value, data, terminates, message.completion).await
+    }
+}
+
+fn schedule_flush(
+    actor_ref: ActorRef<MessageProcessor>,
+    id_value: String,
+    deadline: tokio::time::Instant,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep_until(deadline).await;
+        let _ = actor_ref.tell(FlushDebounced { id_value }).send().await;
+    });
+}
+
+#[derive(Debug, Clone)]
+pub struct FlushDebounced {
+    id_value: String,
+}
+
 #[derive(Debug, Clone)]
-pub struct DispatchSubscription {
-    pub subscription: SubscriptionRecord,
+pub struct DispatchSubscriptions {
+    pub subscriptions: Vec<SubscriptionRecord>,
     id_value: String,
     data: ValueMap,
+    terminates: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{
+        adapters::kv_store::InMemoryKvStoreFactory,
+        ports::router_client::{Request, Response},
+    };
+
+    /// Records the subscription id and action of every request, and the payloads of updates.
+    #[derive(Clone, Default)]
+    struct RecordingRouterClient {
+        requests: Arc<Mutex<Vec<(String, String)>>>,
+        payloads: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    impl RecordingRouterClient {
+        fn requests(&self) -> Vec<(String, String)> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// The entities published to the operation.
+        fn payloads(&self) -> Vec<serde_json::Value> {
+            self.payloads.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl RouterClient for RecordingRouterClient {
+        async fn send(&self, request: &Request) -> anyhow::Result<Response> {
+            let value = |key: &str| request.values[key].as_str().unwrap().to_string();
+            self.requests.lock().unwrap().push((value("id"), value("action")));
+            if let Some(payload) = request.values.get("payload") {
+                let entity = payload["data"]["chargingSessionChanged"].clone();
+                self.payloads.lock().unwrap().push(entity);
+            }
+            Ok(Response {
+                status_code: StatusCode::NO_CONTENT,
+                subscription_protocol: None,
+                errors: None,
+            })
+        }
+
+        fn clone_box(&self) -> Box<dyn RouterClient> {
+            Box::new(self.clone())
+        }
+    }
+
+    async fn processor(
+        router_client: &RecordingRouterClient,
+        kv_store_factory: &InMemoryKvStoreFactory,
+        subscribe_by: serde_json::Value,
+    ) -> ActorRef<MessageProcessor> {
+        let listener = serde_json::json!({ "subscribe_by": subscribe_by });
+        let topic = serde_json::json!({ "name": "charging_sessions" });
+        processor_with(router_client, kv_store_factory, listener, topic).await
+    }
+
+    /// Spawns a processor with the given settings on top of a minimal listener.
+    async fn processor_with(
+        router_client: &RecordingRouterClient,
+        kv_store_factory: &InMemoryKvStoreFactory,
+        listener: serde_json::Value,
+        topic: serde_json::Value,
+    ) -> ActorRef<MessageProcessor> {
+        let mut listener_configuration = serde_json::json!({
+            "operation": "chargingSessionChanged",
+            "entity_name": "ChargingSession",
+            "id_key": "id",
+            "ttl_ms": 60000,
+            "topics": [],
+        });
+        listener_configuration
+            .as_object_mut()
+            .unwrap()
+            .extend(listener.as_object().unwrap().clone());
+        let listener_configuration = serde_json::from_value(listener_configuration).unwrap();
+        let topic_configuration = serde_json::from_value(topic).unwrap();
+        MessageProcessor::spawn(
+            0,
+            Box::new(router_client.clone()),
+            Box::new(kv_store_factory.clone()),
+            listener_configuration,
+            topic_configuration,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn subscribe(kv_store_factory: &InMemoryKvStoreFactory, id: &str, id_value: &str) {
+        let mut subscription_store =
+            SubscriptionStore::new(Box::new(kv_store_factory.clone())).await.unwrap();
+        let record = SubscriptionRecord {
+            id: id.to_string(),
+            created_at: delay_queue::current_timestamp_ms() / 1000,
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms: 0,
+            callback_url: "http://router/callback".to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: id_value.to_string(),
+        };
+        subscription_store.insert(&record, 60_000).await.unwrap();
+    }
+
+    /// Hands the message to the processor and waits until it was dispatched.
+    async fn process(
+        processor: &ActorRef<MessageProcessor>,
+        data: serde_json::Value,
+        terminates: bool,
+    ) {
+        let (completion, result) = Completion::new();
+        let data: ValueMap = serde_json::from_value(data).unwrap();
+        let message = TopicMessage {
+            topic: "charging_sessions".to_string(),
+            message: DecodedMessage {
+                id_value: data["id"].as_str().unwrap().to_string(),
+                data,
+                partition: None,
+                op: None,
+                terminates,
+            },
+            completion,
+        };
+        processor.ask(message).send().await.unwrap();
+        result.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_terminating_message_completes_subscriptions() {
+        let router_client = RecordingRouterClient::default();
+        let kv_store_factory = InMemoryKvStoreFactory::new();
+        subscribe(&kv_store_factory, "subscription-1", "abc").await;
+        let processor = processor(&router_client, &kv_store_factory, "id".into()).await;
+
+        process(&processor, serde_json::json!({ "id": "abc", "status": "charging" }), false).await;
+        process(&processor, serde_json::json!({ "id": "abc", "status": "deleted" }), true).await;
+        process(&processor, serde_json::json!({ "id": "abc", "status": "deleted" }), false).await;
+
+        let subscription = |action: &str| ("subscription-1".to_string(), action.to_string());
+        assert_eq!(
+            router_client.requests(),
+            vec![subscription("next"), subscription("next"), subscription("complete")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_by_field() {
+        let router_client = RecordingRouterClient::default();
+        let kv_store_factory = InMemoryKvStoreFactory::new();
+        subscribe(&kv_store_factory, "subscription-1", "account-1").await;
+        subscribe(&kv_store_factory, "subscription-2", "account-2").await;
+        let subscribe_by = serde_json::json!({ "field": "accountId" });
+        let processor = processor(&router_client, &kv_store_factory, subscribe_by).await;
+
+        process(&processor, serde_json::json!({ "id": "abc", "accountId": "account-1" }), false)
+            .await;
+        process(&processor, serde_json::json!({ "id": "def", "accountId": "account-2" }), true)
+            .await;
+        process(&processor, serde_json::json!({ "id": "ghi" }), false).await;
+
+        // Subscriptions by field outlive the termination of a single entity.
+        let requests = router_client.requests();
+        assert_eq!(
+            requests,
+            vec![
+                ("subscription-1".to_string(), "next".to_string()),
+                ("subscription-2".to_string(), "next".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delay_by_field() {
+        let router_client = RecordingRouterClient::default();
+        let kv_store_factory = InMemoryKvStoreFactory::new();
+        subscribe(&kv_store_factory, "subscription-1", "account-1").await;
+        let listener = serde_json::json!({ "subscribe_by": { "field": "accountId" } });
+        let topic = serde_json::json!({ "name": "charging_sessions", "delay_ms": 60000 });
+        let processor = processor_with(&router_client, &kv_store_factory, listener, topic).await;
+
+        process(&processor, serde_json::json!({ "id": "abc", "accountId": "account-1" }), false)
+            .await;
+        process(&processor, serde_json::json!({ "id": "def", "accountId": "account-2" }), false)
+            .await;
+
+        // Only the update of the entity with subscribers is queued, none is dispatched yet.
+        let mut delay_queue =
+            DelayQueue::new(Box::new(kv_store_factory.clone()), "chargingSessionChanged")
+                .await
+                .unwrap();
+        assert_eq!(delay_queue.len().await.unwrap(), 1);
+        assert!(router_client.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_deltas_keep_subscription_field() {
+        let router_client = RecordingRouterClient::default();
+        let kv_store_factory = InMemoryKvStoreFactory::new();
+        subscribe(&kv_store_factory, "subscription-1", "account-1").await;
+        let listener = serde_json::json!({
+            "subscribe_by": { "field": "accountId" },
+            "state_ttl_ms": 60000,
+            "publish_deltas": true,
+        });
+        let topic = serde_json::json!({ "name": "charging_sessions" });
+        let processor = processor_with(&router_client, &kv_store_factory, listener, topic).await;
+
+        let session = |status: &str| serde_json::json!({ "id": "abc", "accountId": "account-1", "status": status });
+        process(&processor, session("charging"), false).await;
+        process(&processor, session("finished"), false).await;
+
+        assert_eq!(
+            router_client.payloads()[1],
+            serde_json::json!({
+                "id": "abc",
+                "accountId": "account-1",
+                "status": "finished",
+                "__typename": "ChargingSession",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_to_all() {
+        let router_client = RecordingRouterClient::default();
+        let kv_store_factory = InMemoryKvStoreFactory::new();
+        subscribe(&kv_store_factory, "subscription-1", ALL_ENTITIES).await;
+        let processor = processor(&router_client, &kv_store_factory, "all".into()).await;
+
+        process(&processor, serde_json::json!({ "id": "abc" }), false).await;
+        process(&processor, serde_json::json!({ "id": "def" }), true).await;
+        process(&processor, serde_json::json!({ "id": "ghi" }), false).await;
+
+        let subscription = |action: &str| ("subscription-1".to_string(), action.to_string());
+        assert_eq!(
+            router_client.requests(),
+            vec![subscription("next"), subscription("next"), subscription("next")]
+        );
+    }
 }
@@ -0,0 +1,224 @@
+use std::{collections::HashMap, sync::Arc};
+
+use kameo::{actor::ActorRef, request::MessageSend};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    configuration,
+    ports::message_consumer::{self, MessageConsumer, MessageConsumerFactory, RawMessage},
+};
+
+use super::{
+    completion::Completion,
+    message_decoder::{MessageDecoder, MessageSelector},
+    topic::{TopicListener, TopicMessage},
+};
+
+/// Max amount of messages of a consumer which are decoded at the same time. When reached, the
+/// consumer waits until the oldest one is handed over to its listeners.
+const MAX_CONCURRENT_DECODES: usize = 64;
+/// Time to wait after the first failed receive, doubled with every further one up to the max.
+const RECV_BACKOFF_MIN_MS: u64 = 100;
+const RECV_BACKOFF_MAX_MS: u64 = 30_000;
+
+/// Decodes the messages of a consumer and hands them to the listeners of their topic. It doesn't
+/// hold any state besides the decoders, so decoding can run on all worker threads at once.
+pub(crate) struct MessageRouter {
+    routes: HashMap<String, Vec<Route>>,
+}
+
+/// Listeners of a topic which decode its messages the same way.
+struct Route {
+    decoder: MessageDecoder,
+    decoding_key: String,
+    selectors: Vec<(MessageSelector, ActorRef<TopicListener>)>,
+}
+
+impl MessageRouter {
+    pub(crate) fn new(
+        listeners: &[(configuration::Listener, ActorRef<TopicListener>)],
+    ) -> anyhow::Result<Self> {
+        let mut routes: HashMap<String, Vec<Route>> = HashMap::new();
+        for (configuration, listener) in listeners {
+            for topic in &configuration.topics {
+                let selector = MessageSelector::new(configuration.id_key.clone(), topic.clone())?;
+                let decoding_key = MessageDecoder::decoding_key(topic)?;
+                let topic_routes = routes.entry(topic.name.clone()).or_default();
+                match topic_routes.iter_mut().find(|route| route.decoding_key == decoding_key) {
+                    Some(route) => route.selectors.push((selector, listener.clone())),
+                    None => topic_routes.push(Route {
+                        decoder: MessageDecoder::new(topic.clone())?,
+                        decoding_key,
+                        selectors: vec![(selector, listener.clone())],
+                    }),
+                }
+            }
+        }
+        Ok(Self { routes })
+    }
+
+    pub(crate) fn topics(&self) -> Vec<String> {
+        self.routes.keys().cloned().collect()
+    }
+
+    /// Decodes the message once per decoding and selects it for the listeners of its topic.
+    /// Listeners which skip the message, e.g. because of their route, are left out.
+    pub(crate) async fn decode(&self, message: &RawMessage) -> anyhow::Result<Vec<RoutedMessage>> {
+        let routes = self.routes.get(&message.topic).map(Vec::as_slice).unwrap_or_default();
+        let mut decoded = Vec::new();
+        for route in routes {
+            let selectors: Vec<_> =
+                route.selectors.iter().filter(|(selector, _)| selector.accepts(message)).collect();
+            if selectors.is_empty() {
+                continue;
+            }
+
+            let Some(data) = route.decoder.decode(message.clone()).await? else {
+                continue;
+            };
+            for (selector, listener) in selectors {
+                if let Some(decoded_message) = selector.select(message, &data) {
+                    let (completion, result) = Completion::new();
+                    let message = TopicMessage {
+                        topic: message.topic.clone(),
+                        message: decoded_message,
+                        completion,
+                    };
+                    decoded.push(RoutedMessage { listener: listener.clone(), message, result });
+                }
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+/// A message decoded for a listener, with the result of its processing.
+pub(crate) struct RoutedMessage {
+    pub listener: ActorRef<TopicListener>,
+    pub message: TopicMessage,
+    pub result: oneshot::Receiver<anyhow::Result<()>>,
+}
+
+/// Consumes the topics of a group of listeners with a single consumer. Every message is handed to
+/// all listeners of its topic, which decide based on their routes whether they process it. Fails
+/// if the consumer can't be created or subscribed, e.g. because the broker is unavailable.
+pub(crate) async fn spawn_message_consumer(
+    group_id: String,
+    offset_reset: &configuration::ListenerOffsetReset,
+    listeners: Vec<(configuration::Listener, ActorRef<TopicListener>)>,
+    message_consumer_factory: Box<dyn MessageConsumerFactory>,
+) -> anyhow::Result<()> {
+    let offset_reset = match offset_reset {
+        configuration::ListenerOffsetReset::Earliest => message_consumer::OffsetReset::Earliest,
+        configuration::ListenerOffsetReset::Latest => message_consumer::OffsetReset::Latest,
+    };
+    let router = MessageRouter::new(&listeners)?;
+    tracing::info! {
+        event = "message_consumer_starting",
+        group_id = group_id,
+        topics = ?router.topics(),
+    };
+
+    let mut message_consumer = message_consumer_factory.create(group_id, offset_reset).await?;
+    message_consumer.subscribe(&router.topics()).await?;
+    tokio::spawn(run_message_consumer(Arc::new(router), Arc::from(message_consumer)));
+    Ok(())
+}
+
+async fn run_message_consumer(
+    router: Arc<MessageRouter>,
+    message_consumer: Arc<dyn MessageConsumer>,
+) {
+    // Messages are decoded concurrently, but handed to the listeners in the order they were
+    // received, which keeps the ordering per entity intact.
+    let (decoding_sender, decoding_receiver) = mpsc::channel(MAX_CONCURRENT_DECODES);
+    tokio::spawn(hand_over_decoded(message_consumer.clone(), decoding_receiver));
+
+    let mut backoff_ms = 0;
+    loop {
+        match message_consumer.recv().await {
+            Ok(message) => {
+                backoff_ms = 0;
+                let message = Arc::new(message);
+                let decoding = tokio::spawn({
+                    let router = router.clone();
+                    let message = message.clone();
+                    async move { router.decode(&message).await }
+                });
+                if decoding_sender.send((message, decoding)).await.is_err() {
+                    return;
+                }
+            }
+            Err(error) => {
+                backoff_ms = (backoff_ms * 2).clamp(RECV_BACKOFF_MIN_MS, RECV_BACKOFF_MAX_MS);
+                tracing::error! {
+                    event = "message_recv_failed",
+                    backoff_ms,
+                    error = ?error
+                };
+                tokio::time::sleep(tokio::time::Duration::from_millis(backoff_ms)).await;
+            }
+        }
+    }
+}
+
+type Decoding = (Arc<RawMessage>, JoinHandle<anyhow::Result<Vec<RoutedMessage>>>);
+
+async fn hand_over_decoded(
+    message_consumer: Arc<dyn MessageConsumer>,
+    mut decoding_receiver: mpsc::Receiver<Decoding>,
+) {
+    while let Some((message, decoding)) = decoding_receiver.recv().await {
+        let routed = match decoding.await.map_err(anyhow::Error::from).and_then(|routed| routed) {
+            Ok(routed) => routed,
+            Err(error) => {
+                tracing::error! {
+                    event = "message_decoding_failed",
+                    topic = message.topic,
+                    partition = message.partition,
+                    offset = message.offset,
+                    error = ?error,
+                };
+                settle(message_consumer.as_ref(), &message, Err(error)).await;
+                continue;
+            }
+        };
+
+        let mut results = Vec::with_capacity(routed.len());
+        for RoutedMessage { listener, message, result } in routed {
+            // If the listener doesn't accept the message, its completion is dropped, which fails
+            // the result.
+            let _ = listener.tell(message).send().await;
+            results.push(async move {
+                result.await.map_err(|_| anyhow::anyhow!("message was dropped unprocessed"))?
+            });
+        }
+        let message_consumer = message_consumer.clone();
+        tokio::spawn(async move {
+            let result = futures_util::future::try_join_all(results).await;
+            settle(message_consumer.as_ref(), &message, result.map(|_| ())).await;
+        });
+    }
+}
+
+/// Acks the message once all listeners processed it. Otherwise it's nacked, so the consumer
+/// redelivers it if supported.
+async fn settle(
+    message_consumer: &dyn MessageConsumer,
+    message: &RawMessage,
+    result: anyhow::Result<()>,
+) {
+    let settled = match result {
+        Ok(()) => message_consumer.ack(message).await,
+        Err(_) => message_consumer.nack(message).await,
+    };
+    if let Err(error) = settled {
+        tracing::error! {
+            event = "message_ack_failed",
+            error = ?error
+        };
+    }
+}
@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 
+use std::sync::Arc;
+
 use config::Config;
 use kameo::{
-    actor::ActorRef, mailbox::unbounded::UnboundedMailbox, message::Message, request::MessageSend,
-    Actor,
+    actor::ActorRef, error::SendError, mailbox::unbounded::UnboundedMailbox, message::Message,
+    reply::DelegatedReply, request::MessageSend, Actor,
 };
 use subscription::SubscriptionListener;
+use subscription_sweeper::SubscriptionSweeper;
 
 use crate::{
     configuration::{self},
@@ -15,12 +18,26 @@ use crate::{
     },
 };
 
+mod authorization;
+mod completion;
+mod debouncer;
+mod delay_queue;
+mod delay_scheduler;
+mod dispatcher;
+mod enricher;
+mod lease;
+mod message_decoder;
 mod message_processor;
+mod message_router;
+mod replay;
 mod router_endpoint;
+mod state_store;
 mod subscription;
 mod subscription_store;
+mod subscription_sweeper;
 mod topic;
 
+pub use replay::replay;
 pub use subscription::IncomingSubscription;
 pub use topic::TopicListener;
 
@@ -29,6 +46,7 @@ pub struct Listener {
     config: Config,
     topic_listeners: HashMap<String, ActorRef<TopicListener>>,
     subscription_listeners: HashMap<String, ActorRef<SubscriptionListener>>,
+    subscription_sweepers: HashMap<String, ActorRef<SubscriptionSweeper>>,
 }
 
 impl Actor for Listener {
@@ -41,6 +59,9 @@ impl Actor for Listener {
         for topic_listener in self.topic_listeners.values() {
             actor_ref.link_child(topic_listener).await;
         }
+        for subscription_sweeper in self.subscription_sweepers.values() {
+            actor_ref.link_child(subscription_sweeper).await;
+        }
         tracing::info! { event = "listener_started", actor=?actor_ref };
         Ok(())
     }
@@ -69,10 +90,32 @@ impl Listener {
             config: config.clone(),
             topic_listeners: HashMap::new(),
             subscription_listeners: HashMap::new(),
+            subscription_sweepers: HashMap::new(),
+        };
+
+        let mut cluster = config.get::<configuration::Cluster>("cluster").unwrap_or_default();
+        let instance_id =
+            cluster.instance_id.get_or_insert_with(|| uuid::Uuid::new_v4().to_string());
+        tracing::info! {
+            event = "cluster_configured",
+            enabled = cluster.enabled,
+            instance_id = instance_id,
         };
 
         let listeners: configuration::Listeners = config.get("listeners")?;
+        // Built up front, so a broken JWKS fails the start instead of the endpoint later on.
+        let authorizer = authorization::Authorizer::new(config)?.map(Arc::new);
+        if authorizer.is_none() {
+            if let Some(listener) = listeners.iter().find(|l| !l.authorization.is_empty()) {
+                anyhow::bail!(
+                    "authorization rules on '{}' require router_endpoint.authorization",
+                    listener.operation
+                );
+            }
+        }
+        let mut consumer_groups: HashMap<String, Vec<_>> = HashMap::new();
         for listener in listeners {
+            validate_listener(&listener)?;
             let subscription_listener = SubscriptionListener::spawn(
                 router_client.clone(),
                 kv_store_factory.clone(),
@@ -81,40 +124,198 @@ impl Listener {
             .await?;
             actor.subscription_listeners.insert(listener.operation.clone(), subscription_listener);
 
+            let subscription_sweeper = SubscriptionSweeper::spawn(
+                router_client.clone(),
+                kv_store_factory.clone(),
+                &cluster,
+                listener.clone(),
+            )
+            .await?;
+            actor.subscription_sweepers.insert(listener.operation.clone(), subscription_sweeper);
+
             let topic_listener = TopicListener::spawn(
                 router_client.clone(),
                 kv_store_factory.clone(),
+                &cluster,
                 listener.clone(),
+            )
+            .await?;
+            actor.topic_listeners.insert(listener.operation.clone(), topic_listener.clone());
+
+            let group_id = listener
+                .consumer_group
+                .clone()
+                .unwrap_or_else(|| listener.operation.to_lowercase());
+            consumer_groups.entry(group_id).or_default().push((listener, topic_listener));
+        }
+
+        for (group_id, listeners) in consumer_groups {
+            let offset_reset = listeners[0].0.auto_offset_reset.clone();
+            if listeners.iter().any(|(listener, _)| listener.auto_offset_reset != offset_reset) {
+                anyhow::bail!(
+                    "listeners of consumer group '{group_id}' need the same auto_offset_reset"
+                );
+            }
+            message_router::spawn_message_consumer(
+                group_id,
+                &offset_reset,
+                listeners,
                 message_consumer_factory.clone(),
             )
             .await?;
-            actor.topic_listeners.insert(listener.operation.clone(), topic_listener);
         }
 
         let actor_ref = kameo::spawn(actor);
 
         let _router_endpoint =
-            router_endpoint::RouterEndpoint::spawn(config, actor_ref.clone()).await;
+            router_endpoint::RouterEndpoint::spawn(config, actor_ref.clone(), authorizer).await;
 
         Ok(actor_ref)
     }
 }
 
 impl Message<IncomingSubscription> for Listener {
-    type Reply = anyhow::Result<()>;
+    type Reply = DelegatedReply<anyhow::Result<()>>;
 
     async fn handle(
         &mut self,
         subscription: IncomingSubscription,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        mut ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        let listener = self.subscription_listeners.get(&subscription.operation);
-        if let Some(listener) = listener {
-            // TODO: this needs to be properly handled to not block the actor
-            listener.ask(subscription).send().await?;
-            Ok(())
-        } else {
-            anyhow::bail!("no listener found for operation '{}'", subscription.operation);
+        let (delegated_reply, reply_sender) = ctx.reply_sender();
+        let listener = self.subscription_listeners.get(&subscription.operation).cloned();
+
+        // The subscription listener replies once the subscription got confirmed by the router,
+        // so we wait for it outside of this actor to keep accepting subscriptions meanwhile.
+        tokio::spawn(async move {
+            let result = if let Some(listener) = listener {
+                listener.ask(subscription).send().await.map_err(handler_error)
+            } else {
+                Err(anyhow::anyhow!("no listener found for operation '{}'", subscription.operation))
+            };
+            if let Some(reply_sender) = reply_sender {
+                reply_sender.send(result);
+            }
+        });
+
+        delegated_reply
+    }
+}
+
+/// Takes the error of the handler out of a failed ask, so callers can tell errors like
+/// `Unauthorized` apart.
+pub(crate) fn handler_error<M>(error: SendError<M, anyhow::Error>) -> anyhow::Error {
+    match error {
+        SendError::HandlerError(error) => error,
+        error => anyhow::anyhow!("{error}"),
+    }
+}
+
+/// Checks settings of the listener which depend on each other.
+fn validate_listener(listener: &configuration::Listener) -> anyhow::Result<()> {
+    if listener.publish_initial_update
+        && listener.subscribe_by != configuration::ListenerSubscribeBy::Id
+    {
+        anyhow::bail!(
+            "publish_initial_update requires subscribe_by id on '{}'",
+            listener.operation
+        );
+    }
+    if listener.publish_deltas {
+        if listener.state_ttl_ms.is_none() {
+            anyhow::bail!("publish_deltas requires state_ttl_ms on '{}'", listener.operation);
+        }
+        // Enriched fields aren't part of the state, so they couldn't be compared.
+        if listener.enrichment.is_some() {
+            anyhow::bail!(
+                "publish_deltas can't be combined with enrichment on '{}'",
+                listener.operation
+            );
         }
     }
+    let argument = listener.subscription_argument();
+    // The state is kept per entity id, so other fields are only known when subscribing by id.
+    if let Some(rule) =
+        listener.authorization.iter().find(|rule| Some(rule.field.as_str()) != argument)
+    {
+        if listener.subscribe_by != configuration::ListenerSubscribeBy::Id {
+            anyhow::bail!(
+                "authorization rule on '{}' of '{}' requires subscribe_by id, or the field to be \
+                the subscription argument",
+                rule.field,
+                listener.operation
+            );
+        }
+        if listener.state_ttl_ms.is_none() {
+            anyhow::bail!(
+                "authorization rule on '{}' of '{}' requires state_ttl_ms",
+                rule.field,
+                listener.operation
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Waits until all messages received so far are dispatched, including debounced ones. Handled by
+/// the topic listener and its processors.
+#[derive(Debug, Clone)]
+pub(crate) struct Drain;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(subscribe_by: &str, state_ttl_ms: Option<u64>) -> configuration::Listener {
+        serde_json::from_value(serde_json::json!({
+            "operation": "chargingSessionChanged",
+            "entity_name": "ChargingSession",
+            "id_key": "id",
+            "subscribe_by": subscribe_by,
+            "ttl_ms": 60000,
+            "state_ttl_ms": state_ttl_ms,
+            "authorization": [{ "claim": "sub", "field": "ownerId" }],
+            "topics": [],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_listener() {
+        assert!(validate_listener(&listener("id", Some(60000))).is_ok());
+        assert!(validate_listener(&listener("id", None)).is_err());
+        assert!(validate_listener(&listener("all", Some(60000))).is_err());
+    }
+
+    #[test]
+    fn test_validate_listener_initial_update() {
+        let mut listener = listener("all", None);
+        listener.authorization.clear();
+        listener.publish_initial_update = true;
+        assert!(validate_listener(&listener).is_err());
+
+        listener.subscribe_by = configuration::ListenerSubscribeBy::Id;
+        assert!(validate_listener(&listener).is_ok());
+    }
+
+    #[test]
+    fn test_validate_listener_deltas() {
+        let mut listener = listener("id", None);
+        listener.authorization.clear();
+        listener.publish_deltas = true;
+        assert!(validate_listener(&listener).is_err());
+
+        listener.state_ttl_ms = Some(60000);
+        assert!(validate_listener(&listener).is_ok());
+
+        listener.enrichment = Some(configuration::ListenerEnrichment {
+            url: "http://charging-sessions/graphql".to_string(),
+            selection: "status".to_string(),
+            headers: HashMap::new(),
+            timeout_ms: Default::default(),
+            cache_ttl_ms: Default::default(),
+            cache_size: Default::default(),
+        });
+        assert!(validate_listener(&listener).is_err());
+    }
 }
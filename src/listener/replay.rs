@@ -0,0 +1,51 @@
+use config::Config;
+use kameo::request::MessageSend;
+
+use crate::{
+    configuration,
+    ports::{
+        kv_store::KvStoreFactory, message_consumer::MessageConsumerFactory,
+        router_client::RouterClient,
+    },
+};
+
+use super::{message_router::MessageRouter, topic::TopicListener, Drain};
+
+/// Reprocesses the messages of a listener received between `from_ms` and `to_ms` through the
+/// regular processing. The consumer group of the listener is left untouched. Returns the amount of
+/// replayed messages.
+pub async fn replay(
+    config: &Config,
+    operation: &str,
+    from_ms: i64,
+    to_ms: Option<i64>,
+    router_client: Box<dyn RouterClient>,
+    kv_store_factory: Box<dyn KvStoreFactory>,
+    message_consumer_factory: Box<dyn MessageConsumerFactory>,
+) -> anyhow::Result<u64> {
+    let listeners: configuration::Listeners = config.get("listeners")?;
+    let Some(listener) = listeners.into_iter().find(|listener| listener.operation == operation)
+    else {
+        anyhow::bail!("no listener found for operation '{operation}'");
+    };
+    let topics: Vec<String> = listener.topics.iter().map(|topic| topic.name.clone()).collect();
+
+    let topic_listener =
+        TopicListener::spawn_processing(router_client, kv_store_factory, &listener).await?;
+    let router = MessageRouter::new(&[(listener, topic_listener.clone())])?;
+    let mut replay_consumer =
+        message_consumer_factory.create_replay(&topics, from_ms, to_ms).await?;
+
+    let mut count = 0;
+    while let Some(message) = replay_consumer.next().await? {
+        for routed in router.decode(&message).await? {
+            routed.listener.tell(routed.message).send().await?;
+        }
+        count += 1;
+    }
+
+    topic_listener.ask(Drain).send().await?;
+    topic_listener.stop_gracefully().await?;
+
+    Ok(count)
+}
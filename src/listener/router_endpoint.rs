@@ -1,25 +1,28 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use crate::{
     graphql::subscription_operation::SubscriptionOperation,
     listener::{self, Listener},
 };
+
+use super::authorization::{Authorizer, Claims, Unauthorized};
 use axum::{
     extract::{ConnectInfo, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
     routing, Json, Router,
 };
 use config::Config;
 use kameo::{
-    actor::ActorRef, mailbox::unbounded::UnboundedMailbox, message::Message, request::MessageSend,
-    Actor,
+    actor::ActorRef, mailbox::unbounded::UnboundedMailbox, message::Message, reply::DelegatedReply,
+    request::MessageSend, Actor,
 };
 use serde::{Deserialize, Serialize};
 
 pub struct RouterEndpoint {
     config: Config,
     listener: ActorRef<Listener>,
+    authorizer: Option<Arc<Authorizer>>,
     subscription_inject_peer: Option<String>,
 }
 
@@ -32,11 +35,11 @@ impl Actor for RouterEndpoint {
         let path = self.config.get_string("router_endpoint.path")?;
         let listener = tokio::net::TcpListener::bind((hostname.clone(), port)).await?;
 
-        let context = Context { endpoint: actor_ref.clone() };
+        let context = Context { endpoint: actor_ref.clone(), authorizer: self.authorizer.clone() };
         let app =
             Router::new().route(&path, routing::post(graphql_handler)).with_state(context.clone());
 
-        let _ = tokio::spawn(async move {
+        tokio::spawn(async move {
             tracing::info! { event = "server_starting", hostname, port, path };
             match axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
@@ -53,10 +56,15 @@ impl Actor for RouterEndpoint {
 }
 
 impl RouterEndpoint {
-    pub async fn spawn(config: &Config, listener: ActorRef<Listener>) -> ActorRef<Self> {
+    pub async fn spawn(
+        config: &Config,
+        listener: ActorRef<Listener>,
+        authorizer: Option<Arc<Authorizer>>,
+    ) -> ActorRef<Self> {
         kameo::spawn(Self {
             config: config.clone(),
             listener,
+            authorizer,
             subscription_inject_peer: config
                 .get_string("router_endpoint.subscription.inject_peer")
                 .ok(),
@@ -64,58 +72,96 @@ impl RouterEndpoint {
     }
 }
 
-impl Message<(MessageFromRouter, SocketAddr)> for RouterEndpoint {
-    type Reply = anyhow::Result<serde_json::Value>;
+impl Message<(MessageFromRouter, SocketAddr, Option<Claims>)> for RouterEndpoint {
+    type Reply = DelegatedReply<anyhow::Result<serde_json::Value>>;
 
     async fn handle(
         &mut self,
-        (msg, peer_address): (MessageFromRouter, SocketAddr),
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        (msg, peer_address, claims): (MessageFromRouter, SocketAddr, Option<Claims>),
+        mut ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         tracing::debug! { event = "incoming_message", ?msg };
-        // check if we have a subscription extension in the incoming message
-        if let Some(sub_ext) = msg.extensions.and_then(|e| e.subscription) {
-            if let Some(operation) = SubscriptionOperation::from_query(&msg.query, msg.variables) {
-                let callback_url = if let Some(inject_peer) = &self.subscription_inject_peer {
-                    sub_ext
-                        .callback_url
-                        .replace(inject_peer, peer_address.ip().to_string().as_ref())
-                } else {
-                    sub_ext.callback_url
-                };
-
-                self.listener
-                    .ask(listener::IncomingSubscription {
-                        id: sub_ext.subscription_id,
-                        verifier: sub_ext.verifier,
-                        heartbeat_interval_ms: sub_ext.heartbeat_interval_ms,
-                        callback_url,
-                        arguments: operation.arguments,
-                        operation: operation.name,
-                    })
+        let (delegated_reply, reply_sender) = ctx.reply_sender();
+        let incoming_subscription = self.incoming_subscription(msg, peer_address, claims);
+        let listener = self.listener.clone();
+
+        tokio::spawn(async move {
+            let result = match incoming_subscription {
+                Some(subscription) => listener
+                    .ask(subscription)
                     .send()
-                    .await?;
-
-                return Ok(serde_json::json!({
-                    "data": null
-                }));
+                    .await
+                    .map(|_| serde_json::json!({ "data": null }))
+                    .map_err(listener::handler_error),
+                None => Err(anyhow::anyhow!("not implemented")),
+            };
+            if let Some(reply_sender) = reply_sender {
+                reply_sender.send(result);
             }
-        }
+        });
 
-        anyhow::bail!("not implemented");
+        delegated_reply
+    }
+}
+
+impl RouterEndpoint {
+    fn incoming_subscription(
+        &self,
+        msg: MessageFromRouter,
+        peer_address: SocketAddr,
+        claims: Option<Claims>,
+    ) -> Option<listener::IncomingSubscription> {
+        // check if we have a subscription extension in the incoming message
+        let sub_ext = msg.extensions.and_then(|e| e.subscription)?;
+        let operation = SubscriptionOperation::from_query(&msg.query, msg.variables)?;
+        let callback_url = if let Some(inject_peer) = &self.subscription_inject_peer {
+            sub_ext.callback_url.replace(inject_peer, peer_address.ip().to_string().as_ref())
+        } else {
+            sub_ext.callback_url
+        };
+
+        Some(listener::IncomingSubscription {
+            id: sub_ext.subscription_id,
+            verifier: sub_ext.verifier,
+            heartbeat_interval_ms: sub_ext.heartbeat_interval_ms,
+            callback_url,
+            arguments: operation.arguments,
+            operation: operation.name,
+            claims,
+        })
     }
 }
 
 async fn graphql_handler(
     ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
     State(context): State<Context>,
+    headers: HeaderMap,
     Json(input): Json<MessageFromRouter>,
 ) -> impl IntoResponse {
     tracing::debug! { event = "incoming_request", request = ?input, ?peer_addr };
-    let result = context.endpoint.ask((input, peer_addr)).send().await;
+    let claims = match &context.authorizer {
+        Some(authorizer) => {
+            // Without a token, only subscriptions of listeners without rules are accepted.
+            let token = headers.get(authorizer.header()).and_then(|value| value.to_str().ok());
+            match token.map(|token| authorizer.verify(token)) {
+                Some(Ok(claims)) => Some(claims),
+                Some(Err(error)) => {
+                    tracing::warn! { event = "token_rejected", ?error, ?peer_addr };
+                    return (StatusCode::UNAUTHORIZED, Json(None));
+                }
+                None => None,
+            }
+        }
+        None => None,
+    };
+    let result = context.endpoint.ask((input, peer_addr, claims)).send().await;
 
-    match result {
+    match result.map_err(listener::handler_error) {
         Ok(response) => (StatusCode::OK, Json(Some(response))),
+        Err(error) if error.is::<Unauthorized>() => {
+            tracing::warn! { event = "subscription_forbidden", ?error, ?peer_addr };
+            (StatusCode::FORBIDDEN, Json(None))
+        }
         Err(error) => {
             tracing::error! {event = "request_failed", ?error};
             (StatusCode::INTERNAL_SERVER_ERROR, Json(None))
@@ -126,6 +172,7 @@ async fn graphql_handler(
 #[derive(Clone, Debug)]
 struct Context {
     endpoint: ActorRef<RouterEndpoint>,
+    authorizer: Option<Arc<Authorizer>>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use crate::ports::{
+    data_serde::ValueMap,
+    kv_store::{KvStore, KvStoreFactory},
+};
+
+use super::subscription_store::SubscriptionKey;
+
+/// Keeps the last known state of every entity, so new subscribers can get it as initial update
+/// instead of waiting for the next event. Every field of the state is a key of its map, so events
+/// updating different fields at the same time don't overwrite each other.
+#[derive(Clone)]
+pub(crate) struct StateStore {
+    kv_store: Box<dyn KvStore>,
+    ttl_ms: u64,
+}
+
+impl StateStore {
+    pub async fn new(
+        kv_store_factory: Box<dyn KvStoreFactory>,
+        ttl_ms: u64,
+    ) -> anyhow::Result<Self> {
+        let kv_store = kv_store_factory.create().await?;
+        Ok(Self { kv_store, ttl_ms })
+    }
+
+    pub async fn get(&mut self, key: &SubscriptionKey) -> anyhow::Result<Option<ValueMap>> {
+        from_map(self.kv_store.get_map(state_key(key)).await?)
+    }
+
+    /// Merges the data of an event into the state of the entity, as events don't necessarily
+    /// carry all fields. Resets the TTL. Returns the previous state.
+    pub async fn update(
+        &mut self,
+        key: &SubscriptionKey,
+        data: &ValueMap,
+    ) -> anyhow::Result<Option<ValueMap>> {
+        let values = data
+            .iter()
+            .map(|(field, value)| Ok((field.clone(), serde_json::to_vec(value)?)))
+            .collect::<anyhow::Result<_>>()?;
+        from_map(self.kv_store.merge_map(state_key(key), values, self.ttl_ms).await?)
+    }
+
+    pub async fn delete(&mut self, key: &SubscriptionKey) -> anyhow::Result<()> {
+        self.kv_store.delete_map(state_key(key)).await
+    }
+}
+
+/// Returns the fields of `data` which are missing or different in the previous state. `ignored`
+/// fields don't count as change, but are kept if anything else changed.
+pub(crate) fn changed_fields(previous: &ValueMap, data: ValueMap, ignored: &[String]) -> ValueMap {
+    let changed = |key: &String, value: &serde_json::Value| {
+        !ignored.contains(key) && previous.get(key) != Some(value)
+    };
+    if !data.iter().any(|(key, value)| changed(key, value)) {
+        return ValueMap::new();
+    }
+    data.into_iter().filter(|(key, value)| ignored.contains(key) || changed(key, value)).collect()
+}
+
+fn from_map(map: HashMap<String, Vec<u8>>) -> anyhow::Result<Option<ValueMap>> {
+    if map.is_empty() {
+        return Ok(None);
+    }
+    let state = map
+        .into_iter()
+        .map(|(field, value)| Ok((field, serde_json::from_slice(&value)?)))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Some(state))
+}
+
+/// Keeps the hash tag of the subscription key.
+fn state_key(key: &SubscriptionKey) -> String {
+    format!("state:{key}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::kv_store::InMemoryKvStoreFactory;
+
+    #[tokio::test]
+    async fn test_update_merges_state() {
+        let mut store =
+            StateStore::new(Box::new(InMemoryKvStoreFactory::new()), 60_000).await.unwrap();
+        let key = SubscriptionKey {
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        };
+        let data = |key: &str, value: &str| HashMap::from([(key.to_string(), value.into())]);
+
+        assert_eq!(store.update(&key, &data("status", "started")).await.unwrap(), None);
+        assert_eq!(
+            store.update(&key, &data("power", "11kW")).await.unwrap(),
+            Some(data("status", "started"))
+        );
+
+        let mut expected = data("status", "started");
+        expected.extend(data("power", "11kW"));
+        assert_eq!(store.get(&key).await.unwrap(), Some(expected));
+
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let previous = HashMap::from([
+            ("id".to_string(), serde_json::json!("abc")),
+            ("status".to_string(), serde_json::json!("started")),
+            ("power".to_string(), serde_json::json!(11)),
+        ]);
+        let data = HashMap::from([
+            ("id".to_string(), serde_json::json!("abc")),
+            ("status".to_string(), serde_json::json!("charging")),
+            ("power".to_string(), serde_json::json!(11)),
+            ("energy".to_string(), serde_json::json!(0.5)),
+            ("updatedAt".to_string(), serde_json::json!(2)),
+        ]);
+        let ignored = ["updatedAt".to_string()];
+
+        assert_eq!(
+            changed_fields(&previous, data.clone(), &ignored),
+            HashMap::from([
+                ("status".to_string(), serde_json::json!("charging")),
+                ("energy".to_string(), serde_json::json!(0.5)),
+                ("updatedAt".to_string(), serde_json::json!(2)),
+            ])
+        );
+        let mut unchanged = data.clone();
+        unchanged.insert("updatedAt".to_string(), serde_json::json!(3));
+        assert!(changed_fields(&data, unchanged, &ignored).is_empty());
+    }
+}
@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use kameo::{
-    actor::ActorRef, mailbox::bounded::BoundedMailbox, message::Message, request::MessageSend,
+    actor::ActorRef, mailbox::bounded::BoundedMailbox, message::Message, reply::DelegatedReply,
     Actor,
 };
+use tokio::sync::Semaphore;
 
 use crate::{
     configuration,
@@ -13,14 +14,23 @@ use crate::{
     },
 };
 
-use super::subscription_store::{SubscriptionRecord, SubscriptionStore};
+use super::{
+    authorization::{self, Claims},
+    state_store::StateStore,
+    subscription_store::{SubscriptionRecord, SubscriptionStore, ALL_ENTITIES},
+};
 
 const MAILBOX_CAP: usize = 256;
+/// Max amount of subscriptions which are registered (stored + checked) at the same time for a
+/// single operation. When reached, the mailbox fills up and callers are slowed down.
+const MAX_CONCURRENT_REGISTRATIONS: usize = 64;
 
 pub(crate) struct SubscriptionListener {
     router_client: Box<dyn RouterClient>,
     subscription_store: SubscriptionStore,
+    state_store: Option<StateStore>,
     listener_configuration: configuration::Listener,
+    registration_permits: Arc<Semaphore>,
 }
 
 impl Actor for SubscriptionListener {
@@ -59,8 +69,17 @@ impl SubscriptionListener {
         listener_configuration: configuration::Listener,
     ) -> anyhow::Result<ActorRef<Self>> {
         let subscription_store = SubscriptionStore::new(kv_store_factory.clone()).await?;
-        let actor_ref =
-            kameo::spawn(Self { router_client, subscription_store, listener_configuration });
+        let state_store = match listener_configuration.state_ttl_ms {
+            Some(ttl_ms) => Some(StateStore::new(kv_store_factory.clone(), ttl_ms).await?),
+            None => None,
+        };
+        let actor_ref = kameo::spawn(Self {
+            router_client,
+            subscription_store,
+            state_store,
+            listener_configuration,
+            registration_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_REGISTRATIONS)),
+        });
 
         Ok(actor_ref)
     }
@@ -68,75 +87,225 @@ impl SubscriptionListener {
     fn current_timestamp(&self) -> std::time::Duration {
         std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default()
     }
+
+    fn subscription_record(
+        &self,
+        subscription: IncomingSubscription,
+    ) -> anyhow::Result<SubscriptionRecord> {
+        let operation_id_value = match self.listener_configuration.subscription_argument() {
+            Some(argument) => match subscription.arguments.get(argument) {
+                Some(value) => value.to_string(),
+                None => anyhow::bail!("invalid identifier supplied - expected {argument}"),
+            },
+            None => ALL_ENTITIES.to_string(),
+        };
+
+        Ok(SubscriptionRecord {
+            id: subscription.id,
+            operation: subscription.operation,
+            operation_id_value,
+            created_at: self.current_timestamp().as_secs(),
+            verifier: subscription.verifier,
+            heartbeat_interval_ms: subscription.heartbeat_interval_ms,
+            callback_url: subscription.callback_url,
+        })
+    }
 }
 
 impl Message<IncomingSubscription> for SubscriptionListener {
-    type Reply = anyhow::Result<()>;
+    type Reply = DelegatedReply<anyhow::Result<()>>;
 
     async fn handle(
         &mut self,
         subscription: IncomingSubscription,
-        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        mut ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         tracing::debug! { event = "subscription_received", ?subscription };
+        let (delegated_reply, reply_sender) = ctx.reply_sender();
 
-        let ins = subscription.clone();
-        let operation_id_value =
-            if let Some(value) = ins.arguments.get(&self.listener_configuration.id_key) {
-                value.to_string()
-            } else {
-                anyhow::bail!(
-                    "invalid identifier supplied - expected {}",
-                    &self.listener_configuration.id_key
-                );
-            };
-        let subscription = SubscriptionRecord {
-            id: ins.id,
-            operation: ins.operation,
-            operation_id_value,
-            created_at: self.current_timestamp().as_secs(),
-            verifier: ins.verifier,
-            heartbeat_interval_ms: ins.heartbeat_interval_ms,
-            callback_url: ins.callback_url,
+        let claims = subscription.claims.clone();
+        let subscription = match self.subscription_record(subscription) {
+            Ok(subscription) => subscription,
+            Err(error) => {
+                if let Some(reply_sender) = reply_sender {
+                    reply_sender.send(Err(error));
+                }
+                return delegated_reply;
+            }
         };
-        self.subscription_store.insert(&subscription, self.listener_configuration.ttl_ms).await?;
 
-        let check_request = router_client::Request::subscription(
-            &subscription.callback_url,
-            &subscription.id,
-            &subscription.verifier,
-        )
-        .check()
-        .to_owned();
+        // Waiting for a permit is the only thing blocking this actor, so a slow router only
+        // stalls intake once the configured amount of registrations is in flight.
+        let permit = match self.registration_permits.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(error) => {
+                if let Some(reply_sender) = reply_sender {
+                    reply_sender.send(Err(error.into()));
+                }
+                return delegated_reply;
+            }
+        };
 
-        let _check_response = self
-            .router_client
-            .send(&check_request)
+        let router_client = self.router_client.clone();
+        let mut subscription_store = self.subscription_store.clone();
+        let state_store = self.state_store.clone();
+        let listener_configuration = self.listener_configuration.clone();
+        tokio::spawn(async move {
+            let result = match authorize_subscription(
+                state_store.clone(),
+                &listener_configuration,
+                &subscription,
+                claims.as_ref(),
+            )
             .await
-            .map(|response| {
-                tracing::debug! {
-                    event = "check_request_sent",
-                    check_request=?&check_request,
-                    response=?&response
-                };
-                response
-            })
-            .map_err(|error| {
-                tracing::error! {
-                    event = "check_request_failed",
-                    check_request=?&check_request,
-                    error=?&error
-                };
-                error
-            })?;
+            {
+                Ok(()) => {
+                    register_subscription(
+                        router_client.as_ref(),
+                        &mut subscription_store,
+                        &subscription,
+                        listener_configuration.ttl_ms,
+                    )
+                    .await
+                }
+                Err(error) => {
+                    tracing::warn! {
+                        event = "authorization_failed",
+                        error = ?error,
+                        subscription_id = subscription.id,
+                        operation_id_value = subscription.operation_id_value,
+                    };
+                    Err(error)
+                }
+            };
+            let is_registered = result.is_ok();
+            if let Some(reply_sender) = reply_sender {
+                reply_sender.send(result);
+            }
 
-        if self.listener_configuration.publish_initial_update {
-            let dispatch = DispatchInitialUpdate { subscription };
-            ctx.actor_ref().tell(dispatch).send().await?;
-        }
+            if is_registered && listener_configuration.publish_initial_update {
+                if let Err(error) = dispatch_initial_update(
+                    router_client.as_ref(),
+                    state_store,
+                    &listener_configuration,
+                    &subscription,
+                )
+                .await
+                {
+                    tracing::warn! {
+                        event = "initial_update_failed",
+                        error = ?error,
+                        subscription_id = subscription.id,
+                    };
+                }
+            }
+            drop(permit);
+        });
 
-        Ok(())
+        delegated_reply
+    }
+}
+
+/// Evaluates the authorization rules of the listener against the subscribed entity. Fields other
+/// than the subscription argument are taken from the last known state of the entity.
+async fn authorize_subscription(
+    state_store: Option<StateStore>,
+    listener_configuration: &configuration::Listener,
+    subscription: &SubscriptionRecord,
+    claims: Option<&Claims>,
+) -> anyhow::Result<()> {
+    let rules = &listener_configuration.authorization;
+    let argument = listener_configuration.subscription_argument();
+    let mut entity = match state_store {
+        Some(mut state_store) if rules.iter().any(|rule| Some(rule.field.as_str()) != argument) => {
+            state_store.get(&subscription.key()).await?.unwrap_or_default()
+        }
+        _ => Default::default(),
+    };
+    if let Some(argument) = argument {
+        entity.insert(argument.to_string(), serde_json::json!(subscription.operation_id_value));
     }
+
+    authorization::authorize(rules, claims, &entity)
+}
+
+/// Stores the subscription and confirms it with the router.
+async fn register_subscription(
+    router_client: &dyn RouterClient,
+    subscription_store: &mut SubscriptionStore,
+    subscription: &SubscriptionRecord,
+    ttl_ms: u64,
+) -> anyhow::Result<()> {
+    subscription_store.insert(subscription, ttl_ms).await?;
+
+    let check_request = router_client::Request::subscription(
+        &subscription.callback_url,
+        &subscription.id,
+        &subscription.verifier,
+    )
+    .check()
+    .to_owned();
+
+    let _check_response = router_client
+        .send(&check_request)
+        .await
+        .inspect(|response| {
+            tracing::debug! {
+                event = "check_request_sent",
+                check_request=?&check_request,
+                response=?&response
+            };
+        })
+        .inspect_err(|error| {
+            tracing::error! {
+                event = "check_request_failed",
+                check_request=?&check_request,
+                error=?&error
+            };
+        })?;
+
+    Ok(())
+}
+
+/// Publishes the last known state of the entity, or only its id if there is none.
+async fn dispatch_initial_update(
+    router_client: &dyn RouterClient,
+    state_store: Option<StateStore>,
+    listener_configuration: &configuration::Listener,
+    subscription: &SubscriptionRecord,
+) -> anyhow::Result<()> {
+    // The id is enough for the router to resolve the entity, so it's published even if the
+    // state can't be read.
+    let state = match state_store {
+        Some(mut state_store) => match state_store.get(&subscription.key()).await {
+            Ok(state) => state,
+            Err(error) => {
+                tracing::warn! {
+                    event = "initial_state_failed",
+                    error = ?error,
+                    subscription_id = subscription.id,
+                };
+                None
+            }
+        },
+        None => None,
+    };
+    let mut data = state.unwrap_or_default();
+    data.insert(
+        listener_configuration.id_key.clone(),
+        serde_json::json!(subscription.operation_id_value),
+    );
+
+    let next_request = router_client::Request::subscription(
+        &subscription.callback_url,
+        &subscription.id,
+        &subscription.verifier,
+    )
+    .next(&listener_configuration.operation, &listener_configuration.entity_name, data)
+    .to_owned();
+    let _ = router_client.send(&next_request).await?;
+
+    Ok(())
 }
 
 type OperationArguments = HashMap<String, String>;
@@ -149,39 +318,158 @@ pub struct IncomingSubscription {
     pub callback_url: String,
     pub operation: String,
     pub arguments: OperationArguments,
+    /// Verified claims of the token forwarded by the router, if authorization is configured.
+    pub claims: Option<Claims>,
 }
 
-#[derive(Debug, Clone)]
-struct DispatchInitialUpdate {
-    subscription: SubscriptionRecord,
-}
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use kameo::request::MessageSend;
+    use reqwest::StatusCode;
 
-impl Message<DispatchInitialUpdate> for SubscriptionListener {
-    type Reply = anyhow::Result<()>;
+    use super::*;
+    use crate::{
+        adapters::{kv_store::InMemoryKvStoreFactory, router_client::InMemoryRouterClient},
+        listener::subscription_store::SubscriptionKey,
+        ports::router_client::{Request, Response},
+    };
 
-    async fn handle(
-        &mut self,
-        message: DispatchInitialUpdate,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
-    ) -> Self::Reply {
-        let data = HashMap::from_iter(vec![(
-            self.listener_configuration.id_key.clone(),
-            serde_json::json!(message.subscription.operation_id_value),
-        )]);
-
-        let next_request = router_client::Request::subscription(
-            &message.subscription.callback_url,
-            &message.subscription.id,
-            &message.subscription.verifier,
+    #[derive(Clone)]
+    struct SlowRouterClient {}
+
+    #[async_trait]
+    impl RouterClient for SlowRouterClient {
+        async fn send(&self, _request: &Request) -> anyhow::Result<Response> {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            Ok(Response {
+                status_code: StatusCode::NO_CONTENT,
+                subscription_protocol: None,
+                errors: None,
+            })
+        }
+
+        fn clone_box(&self) -> Box<dyn RouterClient> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn listener_configuration() -> configuration::Listener {
+        configuration::Listener {
+            operation: "chargingSessionChanged".to_string(),
+            entity_name: "ChargingSession".to_string(),
+            description: None,
+            id_key: "id".to_string(),
+            subscribe_by: configuration::ListenerSubscribeBy::Id,
+            ttl_ms: 60_000,
+            publish_initial_update: false,
+            state_ttl_ms: None,
+            publish_deltas: false,
+            auto_offset_reset: configuration::ListenerOffsetReset::Latest,
+            consumer_group: None,
+            enrichment: None,
+            authorization: vec![],
+            topics: vec![],
+        }
+    }
+
+    fn incoming_subscription(id: usize) -> IncomingSubscription {
+        IncomingSubscription {
+            id: format!("subscription-{id}"),
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms: 0,
+            callback_url: "http://router/callback".to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            arguments: HashMap::from_iter(vec![("id".to_string(), format!("entity-{id}"))]),
+            claims: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscriptions_are_registered_concurrently() {
+        let listener = SubscriptionListener::spawn(
+            Box::new(SlowRouterClient {}),
+            Box::new(InMemoryKvStoreFactory::new()),
+            listener_configuration(),
         )
-        .next(
-            &self.listener_configuration.operation,
-            &self.listener_configuration.entity_name,
-            data,
+        .await
+        .unwrap();
+
+        let started_at = tokio::time::Instant::now();
+        let results = futures_util::future::join_all(
+            (0..10).map(|id| listener.ask(incoming_subscription(id)).send()),
         )
-        .to_owned();
-        let _ = self.router_client.send(&next_request).await?;
+        .await;
 
-        Ok(())
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert!(started_at.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_without_identifier_is_rejected() {
+        let listener = SubscriptionListener::spawn(
+            Box::new(SlowRouterClient {}),
+            Box::new(InMemoryKvStoreFactory::new()),
+            listener_configuration(),
+        )
+        .await
+        .unwrap();
+
+        let mut subscription = incoming_subscription(0);
+        subscription.arguments.clear();
+
+        assert!(listener.ask(subscription).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_to_all_entities() {
+        let mut listener_configuration = listener_configuration();
+        listener_configuration.subscribe_by = configuration::ListenerSubscribeBy::All;
+        let listener = SubscriptionListener::spawn(
+            Box::new(InMemoryRouterClient::new()),
+            Box::new(InMemoryKvStoreFactory::new()),
+            listener_configuration,
+        )
+        .await
+        .unwrap();
+
+        let mut subscription = incoming_subscription(0);
+        subscription.arguments.clear();
+
+        assert!(listener.ask(subscription).send().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_requires_matching_claim() {
+        let mut listener_configuration = listener_configuration();
+        listener_configuration.authorization = vec![configuration::ListenerAuthorizationRule {
+            claim: "sub".to_string(),
+            field: "ownerId".to_string(),
+        }];
+        listener_configuration.state_ttl_ms = Some(60_000);
+        let kv_store_factory = InMemoryKvStoreFactory::new();
+        let listener = SubscriptionListener::spawn(
+            Box::new(InMemoryRouterClient::new()),
+            Box::new(kv_store_factory.clone()),
+            listener_configuration,
+        )
+        .await
+        .unwrap();
+
+        let mut state_store = StateStore::new(Box::new(kv_store_factory), 60_000).await.unwrap();
+        let key = SubscriptionKey {
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "entity-0".to_string(),
+        };
+        let state = HashMap::from([("ownerId".to_string(), serde_json::json!("user-1"))]);
+        state_store.update(&key, &state).await.unwrap();
+
+        let subscription = |sub: &str| IncomingSubscription {
+            claims: serde_json::json!({ "sub": sub }).as_object().cloned(),
+            ..incoming_subscription(0)
+        };
+        assert!(listener.ask(subscription("user-1")).send().await.is_ok());
+        assert!(listener.ask(subscription("user-2")).send().await.is_err());
+        assert!(listener.ask(incoming_subscription(0)).send().await.is_err());
     }
 }
@@ -3,6 +3,12 @@ use serde::{Deserialize, Serialize};
 
 use crate::ports::kv_store::{KvStore, KvStoreFactory};
 
+/// Stands in for the id value of subscriptions to all entities of an operation.
+pub(crate) const ALL_ENTITIES: &str = "*";
+/// Time after which a claimed check is claimed again if it wasn't rescheduled or removed.
+pub(crate) const CHECK_VISIBILITY_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Clone)]
 pub(crate) struct SubscriptionStore {
     kv_store: Box<dyn KvStore>,
 }
@@ -13,29 +19,140 @@ impl SubscriptionStore {
         Ok(Self { kv_store })
     }
 
+    /// Stores the subscription and schedules its first check (heartbeat or expiry).
     pub async fn insert(&mut self, record: &SubscriptionRecord, ttl_ms: u64) -> anyhow::Result<()> {
         self.kv_store
             .insert_map_key(record.key().to_string(), record.id(), record.value()?, ttl_ms)
-            .await
+            .await?;
+        self.schedule_check(record, record.next_check_at(record.created_at * 1000, ttl_ms)).await
+    }
+
+    pub async fn get(
+        &mut self,
+        key: SubscriptionKey,
+        id: String,
+    ) -> anyhow::Result<Option<SubscriptionRecord>> {
+        let record = match self.get_map(&key).await?.remove(&id) {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        };
+        Ok(record)
     }
 
     pub async fn get_all(
         &mut self,
         key: SubscriptionKey,
     ) -> anyhow::Result<Vec<SubscriptionRecord>> {
-        let map = self
-            .kv_store
-            .get_map(key.to_string())
+        let records = self
+            .get_map(&key)
             .await?
-            .into_values()
-            .map(|value| serde_json::from_slice(&value).unwrap())
-            .collect::<Vec<SubscriptionRecord>>();
+            .into_iter()
+            .filter_map(|(id, value)| match serde_json::from_slice(&value) {
+                Ok(record) => Some(record),
+                // Skipped, so a malformed record doesn't block the others.
+                Err(error) => {
+                    tracing::warn! {
+                        event = "subscription_record_invalid",
+                        key = key.to_string(),
+                        subscription_id = id,
+                        error = ?error,
+                    };
+                    None
+                }
+            })
+            .collect();
 
+        Ok(records)
+    }
+
+    /// Reads the subscriptions stored under the key and under its legacy format.
+    async fn get_map(
+        &mut self,
+        key: &SubscriptionKey,
+    ) -> anyhow::Result<std::collections::HashMap<String, Vec<u8>>> {
+        let mut map = self.kv_store.get_map(key.legacy()).await?;
+        map.extend(self.kv_store.get_map(key.to_string()).await?);
         Ok(map)
     }
 
     pub async fn delete(&mut self, key: SubscriptionKey, id: String) -> anyhow::Result<()> {
-        self.kv_store.delete_map_value(key.to_string(), id).await
+        let check = ScheduledCheck { operation_id_value: key.operation_id_value.clone(), id };
+        self.kv_store.delete_sorted_set_member(checks_key(&key.operation), check.member()?).await?;
+        self.kv_store.delete_map_value(key.legacy(), check.id.clone()).await?;
+        self.kv_store.delete_map_value(key.to_string(), check.id).await
+    }
+
+    pub async fn schedule_check(
+        &mut self,
+        record: &SubscriptionRecord,
+        due_at: u64,
+    ) -> anyhow::Result<()> {
+        let check = ScheduledCheck {
+            operation_id_value: record.operation_id_value.clone(),
+            id: record.id.clone(),
+        };
+        self.kv_store
+            .insert_sorted_set_member(checks_key(&record.operation), check.member()?, due_at)
+            .await
+    }
+
+    /// Takes up to `limit` checks of the operation which are due at `now` (unix ms). Like the
+    /// delay queue, a check stays scheduled until it's rescheduled or deleted, but isn't claimed
+    /// again within the visibility timeout, so it runs at least once.
+    pub async fn claim_due_checks(
+        &mut self,
+        operation: &str,
+        now: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(SubscriptionKey, String)>> {
+        let key = checks_key(operation);
+        let members = self
+            .kv_store
+            .claim_sorted_set_members(key.clone(), now, limit, now + CHECK_VISIBILITY_TIMEOUT_MS)
+            .await?;
+
+        let mut checks = Vec::with_capacity(members.len());
+        for member in members {
+            // A malformed member would be claimed over and over again, so it's removed.
+            let check: ScheduledCheck = match serde_json::from_slice(&member) {
+                Ok(check) => check,
+                Err(error) => {
+                    tracing::warn! {
+                        event = "scheduled_check_invalid",
+                        operation,
+                        error = ?error,
+                    };
+                    self.kv_store.delete_sorted_set_member(key.clone(), member).await?;
+                    continue;
+                }
+            };
+            checks.push((
+                SubscriptionKey {
+                    operation: operation.to_string(),
+                    operation_id_value: check.operation_id_value,
+                },
+                check.id,
+            ));
+        }
+
+        Ok(checks)
+    }
+}
+
+fn checks_key(operation: &str) -> String {
+    format!("checks:{operation}")
+}
+
+/// Member of the sorted set which schedules the checks of all subscriptions of an operation.
+#[derive(Serialize, Deserialize)]
+struct ScheduledCheck {
+    operation_id_value: String,
+    id: String,
+}
+
+impl ScheduledCheck {
+    fn member(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
     }
 }
 
@@ -62,6 +179,21 @@ impl SubscriptionRecord {
     pub fn value(&self) -> anyhow::Result<Vec<u8>> {
         Ok(serde_json::to_vec(self)?)
     }
+
+    /// Unix timestamp in milliseconds at which the subscription has to be terminated.
+    pub fn expires_at(&self, ttl_ms: u64) -> u64 {
+        self.created_at * 1000 + ttl_ms
+    }
+
+    /// Unix timestamp in milliseconds of the next heartbeat after `now`, or the expiry if it
+    /// comes first or the subscription doesn't ask for heartbeats.
+    pub fn next_check_at(&self, now: u64, ttl_ms: u64) -> u64 {
+        let expires_at = self.expires_at(ttl_ms);
+        match self.heartbeat_interval_ms {
+            0 => expires_at,
+            interval => expires_at.min(now + interval),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Into)]
@@ -69,6 +201,15 @@ pub struct SubscriptionKey {
     pub operation: String,
     pub operation_id_value: String,
 }
+
+impl SubscriptionKey {
+    /// Format of the key before it was hash tagged. Subscriptions stored with it are still read
+    /// and deleted, until they expired after an upgrade.
+    pub fn legacy(&self) -> String {
+        format!("{}:{}", self.operation, self.operation_id_value)
+    }
+}
+
 impl From<&SubscriptionRecord> for SubscriptionKey {
     fn from(value: &SubscriptionRecord) -> Self {
         SubscriptionKey {
@@ -78,8 +219,119 @@ impl From<&SubscriptionRecord> for SubscriptionKey {
     }
 }
 
+/// The whole key is wrapped in a hash tag, so in a Redis Cluster all keys derived from it end up
+/// on the same slot.
 impl std::fmt::Display for SubscriptionKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}:{}", self.operation, self.operation_id_value))
+        f.write_fmt(format_args!("{{{}:{}}}", self.operation, self.operation_id_value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscription_key_is_hash_tagged() {
+        let key = SubscriptionKey {
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        };
+
+        assert_eq!(key.to_string(), "{chargingSessionChanged:abc}");
+        assert_eq!(key.legacy(), "chargingSessionChanged:abc");
+    }
+
+    #[tokio::test]
+    async fn test_reads_legacy_keys() {
+        let kv_store_factory: Box<dyn KvStoreFactory> =
+            Box::new(crate::adapters::kv_store::InMemoryKvStoreFactory::new());
+        let mut kv_store = kv_store_factory.create().await.unwrap();
+        let mut store = SubscriptionStore::new(kv_store_factory).await.unwrap();
+        let record = |id: &str| SubscriptionRecord {
+            id: id.to_string(),
+            created_at: 0,
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms: 0,
+            callback_url: "http://router/callback".to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        };
+        let legacy = record("legacy");
+        kv_store
+            .insert_map_key(legacy.key().legacy(), legacy.id(), legacy.value().unwrap(), 60_000)
+            .await
+            .unwrap();
+        store.insert(&record("current"), 60_000).await.unwrap();
+
+        assert_eq!(store.get_all(legacy.key()).await.unwrap().len(), 2);
+        store.delete(legacy.key(), legacy.id()).await.unwrap();
+        assert!(store.get(legacy.key(), legacy.id()).await.unwrap().is_none());
+        assert_eq!(store.get_all(legacy.key()).await.unwrap().len(), 1);
+
+        kv_store
+            .insert_map_key(legacy.key().to_string(), "invalid".to_string(), b"{".to_vec(), 60_000)
+            .await
+            .unwrap();
+        assert_eq!(store.get_all(legacy.key()).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_claim_due_checks_skips_invalid_members() {
+        let kv_store_factory: Box<dyn KvStoreFactory> =
+            Box::new(crate::adapters::kv_store::InMemoryKvStoreFactory::new());
+        let mut kv_store = kv_store_factory.create().await.unwrap();
+        let mut store = SubscriptionStore::new(kv_store_factory).await.unwrap();
+        let record = SubscriptionRecord {
+            id: "subscription-1".to_string(),
+            created_at: 0,
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms: 0,
+            callback_url: "http://router/callback".to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        };
+        kv_store
+            .insert_sorted_set_member(checks_key(&record.operation), b"invalid".to_vec(), 0)
+            .await
+            .unwrap();
+        store.schedule_check(&record, 1).await.unwrap();
+
+        let checks = store.claim_due_checks(&record.operation, 1, 10).await.unwrap();
+
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].1, record.id);
+        // Only the claimed check is left until it's rescheduled or deleted.
+        assert_eq!(kv_store.get_sorted_set_len(checks_key(&record.operation)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_claimed_check_is_claimed_again_after_visibility_timeout() {
+        let kv_store_factory: Box<dyn KvStoreFactory> =
+            Box::new(crate::adapters::kv_store::InMemoryKvStoreFactory::new());
+        let mut store = SubscriptionStore::new(kv_store_factory).await.unwrap();
+        let record = SubscriptionRecord {
+            id: "subscription-1".to_string(),
+            created_at: 0,
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms: 0,
+            callback_url: "http://router/callback".to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        };
+        store.schedule_check(&record, 100).await.unwrap();
+
+        assert_eq!(store.claim_due_checks(&record.operation, 100, 10).await.unwrap().len(), 1);
+        assert!(store.claim_due_checks(&record.operation, 101, 10).await.unwrap().is_empty());
+        let timeout = 100 + CHECK_VISIBILITY_TIMEOUT_MS;
+        assert_eq!(store.claim_due_checks(&record.operation, timeout, 10).await.unwrap().len(), 1);
+
+        // Rescheduling moves the check out of the claim.
+        store.schedule_check(&record, timeout + 1000).await.unwrap();
+        assert!(store
+            .claim_due_checks(&record.operation, timeout + 999, 10)
+            .await
+            .unwrap()
+            .is_empty());
     }
 }
@@ -0,0 +1,301 @@
+use futures_util::StreamExt;
+use kameo::{
+    actor::ActorRef, mailbox::unbounded::UnboundedMailbox, message::Message, request::MessageSend,
+    Actor,
+};
+
+use crate::{
+    configuration,
+    ports::{
+        kv_store::KvStoreFactory,
+        router_client::{self, RouterClient},
+    },
+};
+
+use super::{
+    delay_queue,
+    lease::Lease,
+    subscription_store::{SubscriptionKey, SubscriptionStore},
+};
+
+const POLL_INTERVAL_MS: u64 = 500;
+/// Max amount of checks claimed from the schedule at once.
+const CLAIM_BATCH_SIZE: usize = 128;
+/// Max amount of concurrent requests to the router.
+const CHECK_CONCURRENCY: usize = 16;
+
+/// Sends heartbeats to the router and terminates subscriptions once their TTL is over. Checks are
+/// scheduled in the KV store, so every subscription is checked by a single replica, no matter on
+/// which replica it was created.
+pub(crate) struct SubscriptionSweeper {
+    router_client: Box<dyn RouterClient>,
+    subscription_store: SubscriptionStore,
+    lease: Lease,
+    listener_configuration: configuration::Listener,
+}
+
+impl Actor for SubscriptionSweeper {
+    // Only the poll loop sends messages and it waits for each reply, so the mailbox stays small.
+    type Mailbox = UnboundedMailbox<Self>;
+
+    async fn on_start(&mut self, actor_ref: ActorRef<Self>) -> Result<(), kameo::error::BoxError> {
+        tracing::info! {
+            event = "subscription_sweeper_started",
+            operation = self.listener_configuration.operation,
+            actor = ?actor_ref,
+        };
+
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_millis(POLL_INTERVAL_MS));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            while actor_ref.is_alive() {
+                interval.tick().await;
+                if let Err(error) = actor_ref.ask(SweepSubscriptions).send().await {
+                    tracing::error! { event = "subscription_sweep_failed", ?error };
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl SubscriptionSweeper {
+    pub(crate) async fn spawn(
+        router_client: Box<dyn RouterClient>,
+        kv_store_factory: Box<dyn KvStoreFactory>,
+        cluster: &configuration::Cluster,
+        listener_configuration: configuration::Listener,
+    ) -> anyhow::Result<ActorRef<Self>> {
+        let subscription_store = SubscriptionStore::new(kv_store_factory.clone()).await?;
+        let lease = Lease::new(
+            kv_store_factory,
+            cluster,
+            &format!("sweeper:{}", listener_configuration.operation),
+        )
+        .await?;
+
+        Ok(kameo::spawn(Self { router_client, subscription_store, lease, listener_configuration }))
+    }
+
+    async fn check(&self, key: SubscriptionKey, id: String, now: u64) -> anyhow::Result<()> {
+        let mut subscription_store = self.subscription_store.clone();
+        // Already gone, e.g. it was terminated by a topic. Deleting it again removes a check
+        // which is left over.
+        let Some(subscription) = subscription_store.get(key.clone(), id.clone()).await? else {
+            return subscription_store.delete(key, id).await;
+        };
+
+        let request = router_client::Request::subscription(
+            &subscription.callback_url,
+            &subscription.id,
+            &subscription.verifier,
+        );
+
+        let ttl_ms = self.listener_configuration.ttl_ms;
+        if subscription.expires_at(ttl_ms) <= now {
+            // Fire and forget as we delete the subscription afterwards anyways.
+            let _ = self.router_client.send(&request.clone().complete(None).to_owned()).await;
+            subscription_store.delete(key, id).await?;
+            tracing::debug! {
+                event = "subscription_expired",
+                subscription_id = subscription.id,
+                operation = subscription.operation,
+            };
+            return Ok(());
+        }
+
+        if let Err(error) = self.router_client.send(&request.clone().check().to_owned()).await {
+            // The router doesn't know the subscription anymore, so there is no need to keep it.
+            tracing::warn! {
+                event = "heartbeat_failed",
+                error = ?error,
+                subscription_id = subscription.id,
+                operation = subscription.operation,
+            };
+            return subscription_store.delete(key, id).await;
+        }
+
+        subscription_store
+            .schedule_check(&subscription, subscription.next_check_at(now, ttl_ms))
+            .await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SweepSubscriptions;
+
+impl Message<SweepSubscriptions> for SubscriptionSweeper {
+    type Reply = anyhow::Result<()>;
+
+    async fn handle(
+        &mut self,
+        _message: SweepSubscriptions,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        if !self.lease.acquire().await? {
+            return Ok(());
+        }
+
+        loop {
+            let now = delay_queue::current_timestamp_ms();
+            let checks = self
+                .subscription_store
+                .claim_due_checks(&self.listener_configuration.operation, now, CLAIM_BATCH_SIZE)
+                .await?;
+            let count = checks.len();
+
+            let sweeper = &*self;
+            futures_util::stream::iter(checks)
+                .map(|(key, id)| async move {
+                    // A failed check stays scheduled and is claimed again after the visibility
+                    // timeout.
+                    if let Err(error) = sweeper.check(key, id.clone(), now).await {
+                        tracing::error! {
+                            event = "subscription_check_failed",
+                            error = ?error,
+                            subscription_id = id,
+                        };
+                    }
+                })
+                .buffer_unordered(CHECK_CONCURRENCY)
+                .collect::<()>()
+                .await;
+
+            // When the batch was full, there might be more due checks waiting.
+            if count < CLAIM_BATCH_SIZE {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{
+        adapters::kv_store::InMemoryKvStoreFactory,
+        listener::subscription_store::SubscriptionRecord,
+        ports::router_client::{Request, Response},
+    };
+
+    /// Records the actions of all requests it receives.
+    #[derive(Clone, Default)]
+    struct RecordingRouterClient {
+        actions: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordingRouterClient {
+        fn actions(&self) -> Vec<String> {
+            self.actions.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl RouterClient for RecordingRouterClient {
+        async fn send(&self, request: &Request) -> anyhow::Result<Response> {
+            let action = request.values.get("action").and_then(|action| action.as_str());
+            self.actions.lock().unwrap().push(action.unwrap_or_default().to_string());
+            Ok(Response {
+                status_code: StatusCode::NO_CONTENT,
+                subscription_protocol: None,
+                errors: None,
+            })
+        }
+
+        fn clone_box(&self) -> Box<dyn RouterClient> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn listener_configuration() -> configuration::Listener {
+        serde_json::from_value(serde_json::json!({
+            "operation": "chargingSessionChanged",
+            "entity_name": "ChargingSession",
+            "id_key": "id",
+            "ttl_ms": 60_000,
+            "topics": [],
+        }))
+        .unwrap()
+    }
+
+    fn cluster(instance_id: &str) -> configuration::Cluster {
+        configuration::Cluster {
+            enabled: true,
+            instance_id: Some(instance_id.to_string()),
+            lease_ttl_ms: 10_000.into(),
+        }
+    }
+
+    fn subscription(created_at: u64, heartbeat_interval_ms: u64) -> SubscriptionRecord {
+        SubscriptionRecord {
+            id: "subscription".to_string(),
+            created_at,
+            verifier: "verifier".to_string(),
+            heartbeat_interval_ms,
+            callback_url: "http://router/callback".to_string(),
+            operation: "chargingSessionChanged".to_string(),
+            operation_id_value: "abc".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_is_sent_by_one_replica() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let router_client = RecordingRouterClient::default();
+        let mut replicas = Vec::new();
+        for instance_id in ["a", "b", "c"] {
+            let replica = SubscriptionSweeper::spawn(
+                Box::new(router_client.clone()),
+                kv_store_factory.clone(),
+                &cluster(instance_id),
+                listener_configuration(),
+            )
+            .await
+            .unwrap();
+            replicas.push(replica);
+        }
+
+        // Created a second ago with a heartbeat every second, so the first heartbeat is due.
+        let now = delay_queue::current_timestamp_ms();
+        let mut subscription_store = SubscriptionStore::new(kv_store_factory).await.unwrap();
+        let record = subscription(now / 1000 - 1, 1000);
+        subscription_store.insert(&record, 60_000).await.unwrap();
+
+        for replica in &replicas {
+            replica.ask(SweepSubscriptions).send().await.unwrap();
+        }
+
+        assert_eq!(router_client.actions(), vec!["check"]);
+        assert!(subscription_store.get(record.key(), record.id()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expired_subscription_is_completed() {
+        let kv_store_factory: Box<dyn KvStoreFactory> = Box::new(InMemoryKvStoreFactory::new());
+        let router_client = RecordingRouterClient::default();
+        let sweeper = SubscriptionSweeper::spawn(
+            Box::new(router_client.clone()),
+            kv_store_factory.clone(),
+            &configuration::Cluster::default(),
+            listener_configuration(),
+        )
+        .await
+        .unwrap();
+
+        let now = delay_queue::current_timestamp_ms();
+        let mut subscription_store = SubscriptionStore::new(kv_store_factory).await.unwrap();
+        let record = subscription(now / 1000 - 61, 0);
+        subscription_store.insert(&record, 60_000).await.unwrap();
+
+        sweeper.ask(SweepSubscriptions).send().await.unwrap();
+
+        assert_eq!(router_client.actions(), vec!["complete"]);
+        assert!(subscription_store.get(record.key(), record.id()).await.unwrap().is_none());
+    }
+}
@@ -7,21 +7,20 @@ use kameo::{
 
 use crate::{
     configuration,
-    ports::{
-        kv_store::KvStoreFactory,
-        message_consumer::{self, MessageConsumerFactory},
-        router_client::RouterClient,
-    },
+    ports::{kv_store::KvStoreFactory, router_client::RouterClient},
 };
 
-use super::message_processor::MessageProcessor;
+use super::{
+    completion::Completion, delay_queue::DueMessage, delay_scheduler::DelayScheduler,
+    message_decoder::DecodedMessage, message_processor::MessageProcessor, Drain,
+};
 
 const MAILBOX_CAP: usize = 512;
 
 pub struct TopicListener {
     // configuration: configuration::Listener,
     topics: HashMap<String, configuration::Topic>,
-    message_processors: HashMap<String, ActorRef<MessageProcessor>>,
+    message_processors: HashMap<String, Vec<ActorRef<MessageProcessor>>>,
 }
 
 impl Actor for TopicListener {
@@ -32,7 +31,7 @@ impl Actor for TopicListener {
     }
 
     async fn on_start(&mut self, actor_ref: ActorRef<Self>) -> Result<(), kameo::error::BoxError> {
-        for message_processor in self.message_processors.values() {
+        for message_processor in self.message_processors.values().flatten() {
             actor_ref.link_child(message_processor).await;
         }
         let topics = self.topics.keys();
@@ -46,11 +45,37 @@ impl Actor for TopicListener {
 }
 
 impl TopicListener {
+    /// Spawns the listener with its processors and delay scheduler. Messages are consumed
+    /// separately by `spawn_message_consumer`, as listeners can share a consumer.
     pub(crate) async fn spawn(
         router_client: Box<dyn RouterClient>,
         kv_store_factory: Box<dyn KvStoreFactory>,
+        cluster: &configuration::Cluster,
         configuration: configuration::Listener,
-        message_consumer_factory: Box<dyn MessageConsumerFactory>,
+    ) -> anyhow::Result<ActorRef<Self>> {
+        let actor_ref =
+            Self::spawn_processing(router_client, kv_store_factory.clone(), &configuration).await?;
+
+        if configuration.topics.iter().any(|topic| topic.delay_ms.is_some()) {
+            let delay_scheduler = DelayScheduler::spawn(
+                kv_store_factory.clone(),
+                cluster,
+                actor_ref.clone(),
+                configuration.operation.clone(),
+            )
+            .await?;
+            actor_ref.link_child(&delay_scheduler).await;
+        }
+
+        Ok(actor_ref)
+    }
+
+    /// Spawns the listener and its processors without consuming any messages, the caller feeds
+    /// `TopicMessage`s to it instead.
+    pub(crate) async fn spawn_processing(
+        router_client: Box<dyn RouterClient>,
+        kv_store_factory: Box<dyn KvStoreFactory>,
+        configuration: &configuration::Listener,
     ) -> anyhow::Result<ActorRef<Self>> {
         let topics: HashMap<String, configuration::Topic> =
             configuration.topics.iter().map(|topic| (topic.name.clone(), topic.clone())).collect();
@@ -62,35 +87,40 @@ impl TopicListener {
         };
 
         for topic in &configuration.topics {
-            let message_processor = MessageProcessor::spawn(
-                router_client.clone(),
-                kv_store_factory.clone(),
-                configuration.clone(),
-                topic.clone(),
-            )
-            .await?;
-            actor.message_processors.insert(topic.name.clone(), message_processor);
+            let mut message_processors = Vec::new();
+            for index in 0..topic.processors.0.max(1) {
+                let message_processor = MessageProcessor::spawn(
+                    index,
+                    router_client.clone(),
+                    kv_store_factory.clone(),
+                    configuration.clone(),
+                    topic.clone(),
+                )
+                .await?;
+                message_processors.push(message_processor);
+            }
+            actor.message_processors.insert(topic.name.clone(), message_processors);
         }
 
-        let actor_ref = kameo::spawn(actor);
-
-        let _ = tokio::spawn(run_message_consumer(
-            actor_ref.clone(),
-            configuration.topics.iter().map(|topic| topic.name.clone()).collect(),
-            message_consumer_factory,
-            configuration.operation.clone().to_lowercase(),
-        ));
-
-        Ok(actor_ref)
+        Ok(kameo::spawn(actor))
     }
 }
 
-impl Message<message_consumer::RawMessage> for TopicListener {
+/// A message decoded for this listener, see `MessageRouter`.
+#[derive(Debug)]
+pub(crate) struct TopicMessage {
+    pub topic: String,
+    pub message: DecodedMessage,
+    /// Reports back once the message was processed.
+    pub completion: Completion,
+}
+
+impl Message<TopicMessage> for TopicListener {
     type Reply = ();
 
     async fn handle(
         &mut self,
-        message: message_consumer::RawMessage,
+        message: TopicMessage,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         tracing::debug! {
@@ -98,33 +128,54 @@ impl Message<message_consumer::RawMessage> for TopicListener {
             message = ?message
         }
 
-        if let Some(proccessor) = self.message_processors.get(&message.topic) {
-            let _ = proccessor.tell(message).send().await;
-        }
+        let (Some(topic), Some(processors)) =
+            (self.topics.get(&message.topic), self.message_processors.get(&message.topic))
+        else {
+            return;
+        };
+
+        let index = message.message.processor_index(&topic.partition_by, processors.len());
+        let _ = processors[index].tell(message).send().await;
     }
 }
 
-async fn run_message_consumer(
-    listener: ActorRef<TopicListener>,
-    topics: Vec<String>,
-    message_consumer_factory: Box<dyn message_consumer::MessageConsumerFactory>,
-    group_id: String,
-) {
-    let mut message_consumer = message_consumer_factory.create(group_id).await.unwrap();
-    message_consumer.subscribe(&topics).await.unwrap();
-
-    loop {
-        match message_consumer.recv().await {
-            Ok(message) => {
-                let _ = listener.ask(message).send().await;
-            }
-            Err(error) => {
-                // TODO: proper timeouts/backoffs
-                tracing::error! {
-                    event = "message_recv_failed",
-                    error = ?error
-                };
-            }
+impl Message<DueMessage> for TopicListener {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        message: DueMessage,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let delayed = &message.message;
+        let (Some(topic), Some(processors)) =
+            (self.topics.get(&delayed.topic), self.message_processors.get(&delayed.topic))
+        else {
+            // Dropping the completion keeps the message in the queue, e.g. for an instance which
+            // already knows the topic during a rollout.
+            tracing::warn! {
+                event = "delayed_message_topic_unknown",
+                topic = delayed.topic,
+            };
+            return;
+        };
+
+        let index = delayed.message.processor_index(&topic.partition_by, processors.len());
+        let _ = processors[index].tell(message).send().await;
+    }
+}
+
+impl Message<Drain> for TopicListener {
+    type Reply = anyhow::Result<()>;
+
+    async fn handle(
+        &mut self,
+        _message: Drain,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        for message_processor in self.message_processors.values().flatten() {
+            message_processor.ask(Drain).send().await?;
         }
+        Ok(())
     }
 }
@@ -13,6 +13,15 @@ pub trait KvStore: Send + Sync {
         ttl_ms: u64,
     ) -> anyhow::Result<()>;
 
+    /// Sets several keys in a map at once and returns the map as it was before, atomically. Resets
+    /// the TTL of the map.
+    async fn merge_map(
+        &mut self,
+        key: String,
+        values: HashMap<String, Vec<u8>>,
+        ttl_ms: u64,
+    ) -> anyhow::Result<HashMap<String, Vec<u8>>>;
+
     /// Gets an entire map.
     async fn get_map(&mut self, key: String) -> anyhow::Result<HashMap<String, Vec<u8>>>;
 
@@ -21,6 +30,62 @@ pub trait KvStore: Send + Sync {
 
     /// Deletes an entire map.
     async fn delete_map(&mut self, key: String) -> anyhow::Result<()>;
+
+    /// Adds a member with the given score to a sorted set.
+    async fn insert_sorted_set_member(
+        &mut self,
+        key: String,
+        member: Vec<u8>,
+        score: u64,
+    ) -> anyhow::Result<()>;
+
+    /// Gets up to `limit` members of a sorted set with a score lower or equal to `max_score`,
+    /// ordered by score.
+    async fn get_sorted_set_range(
+        &mut self,
+        key: String,
+        max_score: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// Takes up to `limit` members of a sorted set with a score lower or equal to `max_score`,
+    /// ordered by score, and sets their score to `score`, atomically. This way, a member is only
+    /// returned once until `score` is reached, even if multiple instances compete for it.
+    async fn claim_sorted_set_members(
+        &mut self,
+        key: String,
+        max_score: u64,
+        limit: usize,
+        score: u64,
+    ) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// Deletes a single member of a sorted set. Returns whether the member existed, which makes it
+    /// usable to claim a member when multiple instances compete for it.
+    async fn delete_sorted_set_member(
+        &mut self,
+        key: String,
+        member: Vec<u8>,
+    ) -> anyhow::Result<bool>;
+
+    /// Gets the amount of members in a sorted set.
+    async fn get_sorted_set_len(&mut self, key: String) -> anyhow::Result<u64>;
+
+    /// Acquires a lease for `owner`, or extends it when `owner` already holds it. Returns whether
+    /// `owner` holds the lease afterwards. Leases expire after `ttl_ms` unless they are extended.
+    async fn acquire_lease(
+        &mut self,
+        key: String,
+        owner: String,
+        ttl_ms: u64,
+    ) -> anyhow::Result<bool>;
+
+    fn clone_box(&self) -> Box<dyn KvStore>;
+}
+
+impl Clone for Box<dyn KvStore> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
 }
 
 #[async_trait]
@@ -1,24 +1,79 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct RawMessage {
     pub key: Option<Vec<u8>>,
     pub value: Vec<u8>,
     pub topic: String,
+    /// Headers without a value are left out.
+    pub headers: HashMap<String, Vec<u8>>,
+    /// The partition the message was received on, if the broker partitions its topics.
+    pub partition: Option<i32>,
+    /// Position of the message within its partition.
+    pub offset: Option<i64>,
+    /// Time the message was created or appended, as unix timestamp in milliseconds.
+    pub timestamp: Option<i64>,
+    /// Identifies the message when it's acked, for consumers which can't use the offset, e.g.
+    /// the entry id of a Redis stream.
+    pub ack_id: Option<String>,
 }
 
 #[async_trait]
 pub trait MessageConsumer: Send + Sync {
     /// Subscribes to a list of topics.
-    async fn subscribe(&mut self, topics: &Vec<String>) -> anyhow::Result<()>;
+    async fn subscribe(&mut self, topics: &[String]) -> anyhow::Result<()>;
 
     /// Runs the event loop.
     async fn recv(&self) -> anyhow::Result<RawMessage>;
+
+    /// Acknowledges a message once it was processed. Messages are acked in the order their
+    /// processing finishes, which isn't necessarily the order they were received in. Consumers
+    /// which commit their position on their own don't need to implement it.
+    async fn ack(&self, _message: &RawMessage) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Rejects a message which couldn't be decoded or processed, so it's redelivered if the
+    /// consumer supports it. Either `ack` or `nack` is called for every received message.
+    async fn nack(&self, _message: &RawMessage) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the messages of a time window once, outside of the consumer group of the listener.
+#[async_trait]
+pub trait ReplayConsumer: Send + Sync {
+    /// Returns the next message, or `None` once the end of the window is reached.
+    async fn next(&mut self) -> anyhow::Result<Option<RawMessage>>;
+}
+
+/// Where a new consumer group starts when it has no committed offsets yet.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum OffsetReset {
+    Earliest,
+    #[default]
+    Latest,
 }
 
 #[async_trait]
 pub trait MessageConsumerFactory: Send {
-    async fn create(&self, group_id: String) -> anyhow::Result<Box<dyn MessageConsumer>>;
+    async fn create(
+        &self,
+        group_id: String,
+        offset_reset: OffsetReset,
+    ) -> anyhow::Result<Box<dyn MessageConsumer>>;
+
+    /// Creates a consumer for all messages of `topics` received between `from_ms` and `to_ms`
+    /// (unix ms, inclusive). Without `to_ms`, it reads up to the latest message at the time of
+    /// creation.
+    async fn create_replay(
+        &self,
+        topics: &[String],
+        from_ms: i64,
+        to_ms: Option<i64>,
+    ) -> anyhow::Result<Box<dyn ReplayConsumer>>;
 
     fn clone_box(&self) -> Box<dyn MessageConsumerFactory>;
 }
@@ -22,7 +22,7 @@ impl From<&str> for SubscriptionProtocol {
 }
 
 #[async_trait]
-pub trait RouterClient: Send {
+pub trait RouterClient: Send + Sync {
     async fn send(&self, request: &Request) -> anyhow::Result<Response>;
 
     fn clone_box(&self) -> Box<dyn RouterClient>;
@@ -48,7 +48,7 @@ pub struct Request {
 }
 
 impl Request {
-    pub fn subscription(callback_url: &String, id: &String, verifier: &String) -> Self {
+    pub fn subscription(callback_url: &str, id: &str, verifier: &str) -> Self {
         let mut values = serde_json::Map::new();
         for (key, val) in zip(["id", "verifier", "kind"], [id, verifier, "subscription"]) {
             values.insert(key.to_owned(), serde_json::json!(val));